@@ -0,0 +1,269 @@
+use crate::{
+    git_object::GitObject,
+    git_repository::{repo_find, GitRepository},
+    ignore::IgnoreRules,
+    index::{Index, IndexEntry, IndexLock, GITLINK_MODE},
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+    show_ref::ref_resolve,
+};
+use anyhow::Result;
+use bstr::{BString, ByteSlice, ByteVec};
+use std::{
+    collections::BTreeSet,
+    fs::{self, Metadata},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+pub struct AddOptions {
+    // pathspec を省略したら worktree 全体を対象にする
+    pub all: bool,
+    // すでに index にあるものの変更と削除だけを stage する
+    pub update: bool,
+    // 無視されるファイルも追加する
+    pub force: bool,
+}
+
+pub fn cmd_add(paths: Vec<PathBuf>, options: AddOptions) -> Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    add(&repo, &current_dir, &paths, &options)
+}
+
+// paths は current_dir からのパス
+pub fn add(
+    repo: &GitRepository,
+    current_dir: &Path,
+    paths: &[PathBuf],
+    options: &AddOptions,
+) -> Result<()> {
+    anyhow::ensure!(
+        !(options.all && options.update),
+        "-A and -u are mutually exclusive"
+    );
+    let mut specs = paths
+        .iter()
+        .map(|path| pathspec(&repo.worktree, current_dir, path))
+        .collect::<Result<Vec<_>>>()?;
+    if specs.is_empty() {
+        anyhow::ensure!(
+            options.all || options.update,
+            "Nothing specified, nothing added."
+        );
+        specs.push(BString::from(""));
+    }
+    let in_spec = |path: &[u8]| specs.iter().any(|spec| is_under(path, spec));
+
+    // 読む前に lock しておかないと、その間の他の変更を消してしまう
+    let lock = IndexLock::acquire(&repo.gitdir)?;
+    let mut index = Index::read(&repo.gitdir)?;
+    // index を書いたのと同じ秒に変更されたものは stat が同じでも中身が違うかもしれない
+    let index_time = fs::metadata(Index::path(&repo.gitdir))
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs() as u32);
+    let tracked = index
+        .entries
+        .iter()
+        .filter(|e| in_spec(&e.path))
+        .map(|e| e.path.clone())
+        .collect::<BTreeSet<_>>();
+
+    let mut ignore = IgnoreRules::new(&repo.worktree, &repo.gitdir)?;
+    let mut found = BTreeSet::new();
+    let mut ignored = Vec::new();
+    let names = paths.iter().map(|path| path.display().to_string());
+    for (spec, name) in specs.iter().zip(names.chain(Some(".".to_string()))) {
+        let full_path = repo.worktree.join(spec.to_path()?);
+        let meta = fs::symlink_metadata(&full_path).ok();
+        let has_tracked = tracked.iter().any(|t| is_under(t, spec));
+        if meta.is_none() && !has_tracked {
+            anyhow::bail!("pathspec '{}' did not match any files", name);
+        }
+        if options.update || has_tracked && meta.as_ref().is_some_and(|m| !m.is_dir()) {
+            continue;
+        }
+        match meta {
+            Some(meta)
+                if !spec.is_empty()
+                    && !options.force
+                    && ignore.is_ignored(spec, meta.is_dir())? =>
+            {
+                if !has_tracked {
+                    ignored.push(name);
+                }
+            }
+            Some(meta) if meta.is_dir() && (spec.is_empty() || !is_nested_repo(&full_path)) => {
+                walk(&repo.worktree, spec, &mut ignore, options.force, &mut found)?
+            }
+            Some(_) => {
+                found.insert(spec.clone());
+            }
+            None => {}
+        }
+    }
+
+    for path in tracked.iter().chain(&found).collect::<BTreeSet<_>>() {
+        let staged = index.get(path, 0);
+        let full_path = repo.worktree.join(path.to_path()?);
+        let meta = fs::symlink_metadata(&full_path)
+            .ok()
+            .filter(|meta| !meta.is_dir() || is_nested_repo(&full_path));
+        let Some(meta) = meta else {
+            // 消されたか、ディレクトリに置き換えられた
+            index.remove(path);
+            continue;
+        };
+        let entry = if meta.is_dir() {
+            // 別のリポジトリは今の HEAD の commit を gitlink として置く
+            let gitdir = nested_gitdir(&full_path)?;
+            let head = ref_resolve(&gitdir, &gitdir.join("HEAD"))
+                .map_err(|_| anyhow::anyhow!("'{}/' does not have a commit checked out", path))?;
+            if staged.is_some_and(|e| e.mode == GITLINK_MODE && e.sha == head) {
+                continue;
+            }
+            if staged.is_none() {
+                eprintln!("warning: adding embedded git repository: {}", path);
+            }
+            IndexEntry {
+                mode: GITLINK_MODE,
+                ..IndexEntry::new(path.clone(), head, &meta)
+            }
+        } else {
+            if staged.is_some_and(|e| e.mtime.0 < index_time && e.stat_matches(&meta)) {
+                continue;
+            }
+            let sha = blob_write(repo.odb(), &repo.worktree, path, &meta)?;
+            IndexEntry::new(path.clone(), sha, &meta)
+        };
+        // 衝突中の stage と、同じ名前のファイルとディレクトリを取り除く
+        index.remove(path);
+        let mut dir = path.as_slice();
+        while let Some(i) = dir.rfind_byte(b'/') {
+            dir = &dir[..i];
+            index.remove(dir);
+        }
+        index.remove_dir(path);
+        index.add(entry);
+    }
+    lock.commit(&index)?;
+
+    if !ignored.is_empty() {
+        anyhow::bail!(
+            "The following paths are ignored by one of your .gitignore files:\n{}\nUse -f if you really want to add them.",
+            ignored.join("\n")
+        );
+    }
+    Ok(())
+}
+
+// path が spec そのものか、その下にあるか ("" は worktree 全体)
+fn is_under(path: &[u8], spec: &[u8]) -> bool {
+    spec.is_empty()
+        || path
+            .strip_prefix(spec)
+            .is_some_and(|rest| rest.is_empty() || rest[0] == b'/')
+}
+
+// 実行した場所からのパスを worktree からの "/" 区切りのパスにする
+fn pathspec(worktree: &Path, current_dir: &Path, path: &Path) -> Result<BString> {
+    let mut full = PathBuf::new();
+    for c in current_dir.join(path).components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                full.pop();
+            }
+            c => full.push(c),
+        }
+    }
+    let outside = || anyhow::anyhow!("'{}' is outside repository", path.display());
+    let relative = full.strip_prefix(worktree).map_err(|_| outside())?;
+    if relative.starts_with(".git") {
+        return Err(outside());
+    }
+    let mut spec = BString::from(Vec::new());
+    for (i, c) in relative.components().enumerate() {
+        if i > 0 {
+            spec.push(b'/');
+        }
+        spec.push_str(<[u8]>::from_os_str(c.as_os_str()).ok_or_else(outside)?);
+    }
+    Ok(spec)
+}
+
+fn is_nested_repo(dir: &Path) -> bool {
+    dir.join(".git").exists()
+}
+
+// .git はディレクトリか、submodule のように "gitdir: <path>" と書かれたファイル
+fn nested_gitdir(dir: &Path) -> Result<PathBuf> {
+    let dot_git = dir.join(".git");
+    if dot_git.is_dir() {
+        return Ok(dot_git);
+    }
+    let content = fs::read_to_string(&dot_git)?;
+    let target = content
+        .trim_end()
+        .strip_prefix("gitdir: ")
+        .ok_or_else(|| anyhow::anyhow!("invalid gitfile format: {}", dot_git.display()))?;
+    // 相対パスは .git ファイルのあるディレクトリから
+    Ok(dir.join(target))
+}
+
+// 無視されるものは飛ばし、別のリポジトリになっているディレクトリの中には入らない
+fn walk(
+    worktree: &Path,
+    dir: &BString,
+    ignore: &mut IgnoreRules,
+    force: bool,
+    found: &mut BTreeSet<BString>,
+) -> Result<()> {
+    let full_dir = worktree.join(dir.to_path()?);
+    for entry in fs::read_dir(&full_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let Some(name) = <[u8]>::from_os_str(&name) else {
+            continue;
+        };
+        let path = if dir.is_empty() {
+            BString::from(name)
+        } else {
+            BString::from([dir.as_slice(), b"/", name].concat())
+        };
+        let is_dir = entry.file_type()?.is_dir();
+        if !force && ignore.is_ignored(&path, is_dir)? {
+            continue;
+        }
+        if is_dir && is_nested_repo(&entry.path()) {
+            found.insert(path);
+        } else if is_dir {
+            walk(worktree, &path, ignore, force, found)?;
+        } else {
+            found.insert(path);
+        }
+    }
+    Ok(())
+}
+
+// シンボリックリンクはリンク先のパスを中身にする
+fn blob_write(
+    odb: &dyn ObjectDatabase,
+    worktree: &Path,
+    path: &BString,
+    meta: &Metadata,
+) -> Result<ObjectId> {
+    let full_path = worktree.join(path.to_path()?);
+    let content = if meta.file_type().is_symlink() {
+        Vec::from_path_buf(fs::read_link(&full_path)?)
+            .map_err(|p| anyhow::anyhow!("Invalid link target {:?}", p))?
+    } else {
+        fs::read(&full_path)?
+    };
+    Ok(GitObject::Blob { content }.write(odb)?)
+}
@@ -0,0 +1,243 @@
+use crate::{
+    error::{Error, Result},
+    git_object::{tree_read, FileType, TreeOject},
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+};
+use bstr::{BString, ByteSlice};
+use std::collections::{BTreeMap, BTreeSet};
+
+const SEED0: u32 = 0x293a_e76f;
+const SEED1: u32 = 0x7e64_6e2c;
+// これより多くのパスが変わった commit は、すべての bit を立てた 1 byte の filter にする
+const MAX_CHANGED_PATHS: usize = 512;
+
+// BDAT の先頭に書く設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomSettings {
+    // 1 は git 2.39 までの murmur3 (byte を符号付きで扱う)、2 は符号なし
+    pub hash_version: u32,
+    pub num_hashes: u32,
+    pub bits_per_entry: u32,
+}
+
+impl Default for BloomSettings {
+    // 既存の git が読めるように version 1 で書く
+    fn default() -> Self {
+        Self {
+            hash_version: 1,
+            num_hashes: 7,
+            bits_per_entry: 10,
+        }
+    }
+}
+
+fn murmur3(seed: u32, data: &[u8], signed: bool) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let byte = |b: u8| {
+        if signed {
+            b as i8 as i32 as u32
+        } else {
+            b as u32
+        }
+    };
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let k = byte(block[0]) | byte(block[1]) << 8 | byte(block[2]) << 16 | byte(block[3]) << 24;
+        h ^= mix(k);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, b) in tail.iter().enumerate() {
+            k ^= byte(*b) << (8 * i);
+        }
+        h ^= mix(k);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+impl BloomSettings {
+    pub fn parse(header: &[u8]) -> Result<Self> {
+        if header.len() < 12 {
+            return Err(Error::corrupt("Truncated bloom filter header"));
+        }
+        let be32 = |pos: usize| u32::from_be_bytes(header[pos..pos + 4].try_into().unwrap());
+        Ok(Self {
+            hash_version: be32(0),
+            num_hashes: be32(4),
+            bits_per_entry: be32(8),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.hash_version, self.num_hashes, self.bits_per_entry]
+            .iter()
+            .flat_map(|n| n.to_be_bytes())
+            .collect()
+    }
+
+    // filter の中で path に対応する bit の位置
+    fn positions(&self, path: &[u8], filter_len: usize) -> impl Iterator<Item = usize> {
+        let signed = self.hash_version == 1;
+        let h0 = murmur3(SEED0, path, signed);
+        let h1 = murmur3(SEED1, path, signed);
+        let bits = filter_len as u64 * 8;
+        (0..self.num_hashes)
+            .map(move |i| (h0.wrapping_add(i.wrapping_mul(h1)) as u64 % bits) as usize)
+    }
+
+    // 変わったパスが多すぎる (None) ときは「どのパスもあるかもしれない」filter になる
+    pub fn filter(&self, paths: Option<&BTreeSet<BString>>) -> Vec<u8> {
+        let Some(paths) = paths else {
+            return vec![0xff];
+        };
+        let len = (paths.len() * self.bits_per_entry as usize)
+            .div_ceil(8)
+            .max(1);
+        let mut filter = vec![0u8; len];
+        for path in paths {
+            for pos in self.positions(path, len) {
+                filter[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+        filter
+    }
+
+    // false なら path は確実に変わっていない
+    pub fn maybe_contains(&self, filter: &[u8], path: &[u8]) -> bool {
+        if filter.is_empty() {
+            return true;
+        }
+        self.positions(path, filter.len())
+            .all(|pos| filter[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
+
+fn join_path(prefix: &[u8], name: &[u8]) -> BString {
+    if prefix.is_empty() {
+        BString::from(name)
+    } else {
+        BString::from([prefix, b"/", name].concat())
+    }
+}
+
+// 変わったファイル (と submodule) のパスを集める。多すぎたら false
+fn diff_trees(
+    odb: &dyn ObjectDatabase,
+    old: Option<&ObjectId>,
+    new: Option<&ObjectId>,
+    prefix: &[u8],
+    paths: &mut BTreeSet<BString>,
+) -> Result<bool> {
+    let entries = |tree: Option<&ObjectId>| -> Result<BTreeMap<BString, TreeOject>> {
+        Ok(match tree {
+            Some(sha) => tree_read(odb, sha)?
+                .into_iter()
+                .map(|e| (e.path.clone(), e))
+                .collect(),
+            None => BTreeMap::new(),
+        })
+    };
+    let old = entries(old)?;
+    let new = entries(new)?;
+    let names = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    for name in names {
+        let (o, n) = (old.get(name), new.get(name));
+        if let (Some(o), Some(n)) = (o, n) {
+            if o.sha == n.sha && o.mode() == n.mode() {
+                continue;
+            }
+        }
+        let path = join_path(prefix, name);
+        let is_tree = |e: &&TreeOject| matches!(e.file_type, FileType::Tree);
+        if o.filter(|e| !is_tree(e)).is_some() || n.filter(|e| !is_tree(e)).is_some() {
+            paths.insert(path.clone());
+        }
+        let old_tree = o.filter(is_tree).map(|e| &e.sha);
+        let new_tree = n.filter(is_tree).map(|e| &e.sha);
+        if (old_tree.is_some() || new_tree.is_some())
+            && !diff_trees(odb, old_tree, new_tree, &path, paths)?
+        {
+            return Ok(false);
+        }
+        if paths.len() > MAX_CHANGED_PATHS {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// 親の tree (根の commit なら空) から変わったパスと、その上のディレクトリすべて
+// 変わったファイルが多すぎるときは None
+pub fn changed_paths(
+    odb: &dyn ObjectDatabase,
+    old_tree: Option<&ObjectId>,
+    new_tree: &ObjectId,
+) -> Result<Option<BTreeSet<BString>>> {
+    let mut paths = BTreeSet::new();
+    if !diff_trees(odb, old_tree, Some(new_tree), b"", &mut paths)? {
+        return Ok(None);
+    }
+    let dirs = paths
+        .iter()
+        .flat_map(|path| {
+            path.find_iter("/")
+                .map(|i| BString::from(&path[..i]))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    paths.extend(dirs);
+    Ok(Some(paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{murmur3, BloomSettings};
+    use bstr::BString;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn filter() {
+        // 普通の murmur3 と同じ値になる
+        assert_eq!(murmur3(0, b"", false), 0);
+        assert_eq!(murmur3(0, b"Hello world!", false), 0x627b_0c2c);
+        assert_eq!(
+            murmur3(0, b"The quick brown fox jumps over the lazy dog", false),
+            0x2e4f_f723
+        );
+        // version 1 は 0x80 以上の byte だけ結果が変わる
+        assert_eq!(murmur3(7, b"abc", true), murmur3(7, b"abc", false));
+        assert_ne!(
+            murmur3(7, "é".as_bytes(), true),
+            murmur3(7, "é".as_bytes(), false)
+        );
+
+        let settings = BloomSettings::default();
+        let paths = ["a", "a/b.txt", "c"]
+            .into_iter()
+            .map(BString::from)
+            .collect::<BTreeSet<_>>();
+        let filter = settings.filter(Some(&paths));
+        assert_eq!(filter.len(), 4);
+        for path in &paths {
+            assert!(settings.maybe_contains(&filter, path));
+        }
+        let misses = (0..100)
+            .filter(|i| !settings.maybe_contains(&filter, format!("x{}", i).as_bytes()))
+            .count();
+        assert!(misses > 90);
+        assert!(settings.maybe_contains(&settings.filter(None), b"anything"));
+    }
+}
@@ -1,14 +1,28 @@
 use crate::{
-    git_object::{object_read, serialize_object, GitObjectKind},
+    git_object::{object_peel, object_resolve, serialize_object, GitObjectKind},
     git_repository::repo_find,
 };
 use anyhow::Result;
+use std::io::Write;
 
-pub fn cmd_cat_file(_kind: GitObjectKind, object_str: String) -> Result<()> {
+pub fn cmd_cat_file(kind: GitObjectKind, object_str: String) -> Result<()> {
     let current_dir = std::env::current_dir()?;
-    let repo = repo_find(&current_dir)?.gitdir;
-    let object = object_read(&repo, object_str.as_str())?;
-    let object_str = String::from_utf8(serialize_object(&object))?;
-    println!("{}", object_str);
+    let repo = repo_find(&current_dir)?;
+    let odb = repo.odb();
+    let sha = object_resolve(odb, &object_str)?;
+
+    // 種類が一致していれば全体を展開せずにそのまま流す
+    let mut reader = odb.open(&sha)?;
+    if reader.kind == kind {
+        let size = reader.size as u64;
+        let copied = std::io::copy(&mut reader, &mut std::io::stdout().lock())?;
+        anyhow::ensure!(copied == size, "Size mismatch");
+        return Ok(());
+    }
+
+    // `git cat-file commit <tag>` のように指定した種類まで剥がす
+    let (_, object) = object_peel(odb, &sha, kind)?;
+    // 中身は UTF-8 とは限らないのでそのまま書き出す
+    std::io::stdout().write_all(&serialize_object(&object))?;
     Ok(())
 }
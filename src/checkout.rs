@@ -1,19 +1,22 @@
 use anyhow::{Ok, Result};
-use std::{fs, path::PathBuf};
+use bstr::ByteSlice;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    git_object::{object_read, GitObject, TreeOject},
+    git_object::{object_peel, object_resolve, GitObject, GitObjectKind, TreeOject},
     git_repository::repo_find,
+    object_database::ObjectDatabase,
 };
 
 pub fn cmd_checkout(commit: String, path: PathBuf) -> Result<()> {
     let current_dir = std::env::current_dir()?;
-    let gitdir = repo_find(&current_dir)?.gitdir;
-    let tree = match object_read(&gitdir, commit.as_str())? {
-        GitObject::Commit { tree, .. } => object_read(&gitdir, tree.as_str())?,
-        tree @ GitObject::Tree(_) => tree,
-        _ => anyhow::bail!("commit or tree object expected"),
-    };
+    let repo = repo_find(&current_dir)?;
+    let odb = repo.odb();
+    // tag や commit は tree まで剥がす
+    let (_, tree) = object_peel(odb, &object_resolve(odb, &commit)?, GitObjectKind::Tree)?;
     let GitObject::Tree(tree_vec) = tree else {
         anyhow::bail!("tree object expected");
     };
@@ -30,22 +33,22 @@ pub fn cmd_checkout(commit: String, path: PathBuf) -> Result<()> {
 
     fs::create_dir_all(&path)?;
 
-    tree_checkout(&gitdir, &tree_vec, &path)?;
+    tree_checkout(odb, &tree_vec, &path)?;
 
     Ok(())
 }
 
-fn tree_checkout(gitdir: &PathBuf, tree_vec: &[TreeOject], path: &PathBuf) -> Result<()> {
+fn tree_checkout(odb: &dyn ObjectDatabase, tree_vec: &[TreeOject], path: &Path) -> Result<()> {
     for tree_obj in tree_vec {
-        let obj = object_read(gitdir, &tree_obj.sha)?;
-        let obj_path = path.join(&tree_obj.path);
+        let obj = odb.read(&tree_obj.sha)?;
+        let obj_path = path.join(tree_obj.path.to_path()?);
         match obj {
             GitObject::Blob { content } => {
                 fs::write(&obj_path, content)?;
             }
             GitObject::Tree(objects) => {
                 fs::create_dir(&obj_path)?;
-                tree_checkout(gitdir, &objects, &obj_path)?;
+                tree_checkout(odb, &objects, &obj_path)?;
             }
             _ => anyhow::bail!("blob or tree object expected"),
         }
@@ -0,0 +1,99 @@
+use crate::{
+    error::{Error, Result},
+    object_id::ObjectId,
+};
+use std::{collections::HashMap, io::Write};
+
+// commit-graph と multi-pack-index に共通の chunk の表
+// (4 byte の ID, 8 byte の offset) が chunk の数 + 1 個並び、最後の ID 0 の行が終わりを示す
+const ENTRY_LEN: usize = 12;
+
+pub type ChunkId = [u8; 4];
+
+pub fn read_chunks(
+    data: &[u8],
+    table_start: usize,
+    count: usize,
+) -> Result<HashMap<ChunkId, &[u8]>> {
+    let table_end = table_start + (count + 1) * ENTRY_LEN;
+    // 末尾には SHA-1 の checksum がある
+    if data.len() < table_end + ObjectId::LEN {
+        return Err(Error::corrupt("Truncated chunk table"));
+    }
+    let data_end = data.len() - ObjectId::LEN;
+
+    let entry = |i: usize| {
+        let pos = table_start + i * ENTRY_LEN;
+        let id: ChunkId = data[pos..pos + 4].try_into().unwrap();
+        let offset = u64::from_be_bytes(data[pos + 4..pos + ENTRY_LEN].try_into().unwrap());
+        (id, offset as usize)
+    };
+    let mut chunks = HashMap::new();
+    for i in 0..count {
+        let (id, start) = entry(i);
+        let (_, end) = entry(i + 1);
+        if start < table_end || start > end || end > data_end {
+            return Err(Error::corrupt(format!(
+                "Chunk {} is out of range",
+                String::from_utf8_lossy(&id)
+            )));
+        }
+        chunks.insert(id, &data[start..end]);
+    }
+    Ok(chunks)
+}
+
+pub fn write_chunks(
+    w: &mut impl Write,
+    header_len: usize,
+    chunks: &[(ChunkId, Vec<u8>)],
+) -> Result<()> {
+    let mut offset = (header_len + (chunks.len() + 1) * ENTRY_LEN) as u64;
+    for (id, chunk) in chunks {
+        w.write_all(id)?;
+        w.write_all(&offset.to_be_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    w.write_all(&[0; 4])?;
+    w.write_all(&offset.to_be_bytes())?;
+    for (_, chunk) in chunks {
+        w.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+// OIDF: 先頭 1 byte ごとの累積数 (ids は並べ替え済み)
+pub fn fanout_chunk(ids: &[ObjectId]) -> Vec<u8> {
+    let mut fanout = [0u32; 256];
+    for id in ids {
+        fanout[id.as_bytes()[0] as usize] += 1;
+    }
+    let mut total = 0;
+    let mut chunk = Vec::with_capacity(256 * 4);
+    for n in fanout {
+        total += n;
+        chunk.extend_from_slice(&total.to_be_bytes());
+    }
+    chunk
+}
+
+// OIDF と OIDL (20 byte ずつ並んだ SHA-1) から位置を探す
+pub fn fanout_lookup(fanout: &[u8], oids: &[u8], sha: &ObjectId) -> Option<usize> {
+    let count =
+        |i: usize| u32::from_be_bytes(fanout[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+    let first = sha.as_bytes()[0] as usize;
+    let mut lo = if first == 0 { 0 } else { count(first - 1) };
+    let mut hi = count(first);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        match oids
+            .get(mid * ObjectId::LEN..(mid + 1) * ObjectId::LEN)?
+            .cmp(sha.as_bytes())
+        {
+            std::cmp::Ordering::Less => lo = mid + 1,
+            std::cmp::Ordering::Greater => hi = mid,
+            std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
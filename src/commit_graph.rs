@@ -0,0 +1,503 @@
+use crate::{
+    bloom::{changed_paths, BloomSettings},
+    chunk_format::{fanout_chunk, fanout_lookup, read_chunks, write_chunks, ChunkId},
+    error::{Error, Result},
+    git_object::{GitObject, GitObjectKind},
+    git_repository::repo_find,
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+    object_stream::HashWriter,
+    pack::tmp_path,
+    reachable::{links, ref_roots},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const SIGNATURE: &[u8] = b"CGPH";
+const HEADER_LEN: usize = 8;
+const OIDF: ChunkId = *b"OIDF";
+const OIDL: ChunkId = *b"OIDL";
+const CDAT: ChunkId = *b"CDAT";
+const EDGE: ChunkId = *b"EDGE";
+// changed-path Bloom filter: 各 commit の filter の終わりの位置と、filter を並べたもの
+const BIDX: ChunkId = *b"BIDX";
+const BDAT: ChunkId = *b"BDAT";
+const BDAT_HEADER_LEN: usize = 12;
+// CDAT の 1 行: tree, 親 2 つ, 世代番号 (30 bit) と commit の日時 (34 bit)
+const CDAT_LEN: usize = ObjectId::LEN + 16;
+const PARENT_NONE: u32 = 0x7000_0000;
+// 親が 3 つ以上あるときは 2 つ目以降を EDGE に置き、その位置に印を付けて書く
+const EXTRA_EDGES: u32 = 0x8000_0000;
+const GENERATION_MAX: u32 = 0x3fff_ffff;
+const COMMIT_TIME_MAX: i64 = (1 << 34) - 1;
+
+fn be32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+pub struct GraphCommit {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    // 根の commit を 1 として、親の最大 + 1
+    pub generation: u32,
+    pub commit_time: i64,
+}
+
+// objects/info/commit-graph
+pub struct CommitGraph {
+    fanout: Vec<u8>,
+    oids: Vec<u8>,
+    commits: Vec<u8>,
+    edges: Vec<u8>,
+    bloom_settings: Option<BloomSettings>,
+    bloom_index: Vec<u8>,
+    bloom_data: Vec<u8>,
+}
+
+impl CommitGraph {
+    pub fn path(objects_dir: &Path) -> PathBuf {
+        objects_dir.join("info").join("commit-graph")
+    }
+
+    // ファイルがなければ None
+    pub fn open(objects_dir: &Path) -> Result<Option<Self>> {
+        match fs::read(Self::path(objects_dir)) {
+            Ok(data) => Ok(Some(Self::parse(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN || &data[..4] != SIGNATURE {
+            return Err(Error::corrupt("Not a commit-graph file"));
+        }
+        if data[4] != 1 || data[5] != 1 {
+            return Err(Error::corrupt(format!(
+                "Unsupported commit-graph version {} (hash version {})",
+                data[4], data[5]
+            )));
+        }
+        // 分割された commit-graph (base graph がある) には対応しない
+        if data[7] != 0 {
+            return Err(Error::corrupt("Split commit-graph is not supported"));
+        }
+        let chunks = read_chunks(data, HEADER_LEN, data[6] as usize)?;
+        let chunk = |id: ChunkId| {
+            chunks.get(&id).copied().ok_or_else(|| {
+                Error::corrupt(format!(
+                    "commit-graph has no {} chunk",
+                    String::from_utf8_lossy(&id)
+                ))
+            })
+        };
+
+        let fanout = chunk(OIDF)?;
+        if fanout.len() != 256 * 4 {
+            return Err(Error::corrupt("Invalid commit-graph fanout"));
+        }
+        let count = be32(fanout, 255 * 4) as usize;
+        let oids = chunk(OIDL)?;
+        let commits = chunk(CDAT)?;
+        if oids.len() != count * ObjectId::LEN || commits.len() != count * CDAT_LEN {
+            return Err(Error::corrupt("commit-graph chunk sizes do not match"));
+        }
+        // Bloom filter は両方の chunk があるときだけ使う
+        let (bloom_settings, bloom_index, bloom_data) = match (chunks.get(&BIDX), chunks.get(&BDAT))
+        {
+            (Some(index), Some(bdat)) => {
+                if index.len() != count * 4 || bdat.len() < BDAT_HEADER_LEN {
+                    return Err(Error::corrupt("Invalid commit-graph Bloom filter chunks"));
+                }
+                (
+                    Some(BloomSettings::parse(bdat)?),
+                    index.to_vec(),
+                    bdat[BDAT_HEADER_LEN..].to_vec(),
+                )
+            }
+            _ => (None, Vec::new(), Vec::new()),
+        };
+        Ok(Self {
+            fanout: fanout.to_vec(),
+            oids: oids.to_vec(),
+            commits: commits.to_vec(),
+            edges: chunks.get(&EDGE).map(|e| e.to_vec()).unwrap_or_default(),
+            bloom_settings,
+            bloom_index,
+            bloom_data,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.oids.len() / ObjectId::LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.oids.is_empty()
+    }
+
+    fn id(&self, i: u32) -> Result<ObjectId> {
+        let i = i as usize;
+        if i >= self.len() {
+            return Err(Error::corrupt(format!(
+                "commit-graph parent {} is out of range",
+                i
+            )));
+        }
+        ObjectId::from_bytes(&self.oids[i * ObjectId::LEN..(i + 1) * ObjectId::LEN])
+    }
+
+    pub fn contains(&self, sha: &ObjectId) -> bool {
+        fanout_lookup(&self.fanout, &self.oids, sha).is_some()
+    }
+
+    pub fn bloom_settings(&self) -> Option<&BloomSettings> {
+        self.bloom_settings.as_ref()
+    }
+
+    // Bloom filter がない、または範囲がおかしければ None
+    pub fn bloom_filter(&self, sha: &ObjectId) -> Option<&[u8]> {
+        self.bloom_settings.as_ref()?;
+        let i = fanout_lookup(&self.fanout, &self.oids, sha)?;
+        let start = if i == 0 {
+            0
+        } else {
+            be32(&self.bloom_index, (i - 1) * 4) as usize
+        };
+        let end = be32(&self.bloom_index, i * 4) as usize;
+        self.bloom_data.get(start..end)
+    }
+
+    // sha がその最初の親から path を変えたかもしれないなら true
+    pub fn maybe_changed(&self, sha: &ObjectId, path: &[u8]) -> bool {
+        match (self.bloom_settings(), self.bloom_filter(sha)) {
+            (Some(settings), Some(filter)) => settings.maybe_contains(filter, path),
+            _ => true,
+        }
+    }
+
+    // commit-graph に入っていなければ None
+    pub fn commit(&self, sha: &ObjectId) -> Result<Option<GraphCommit>> {
+        let Some(i) = fanout_lookup(&self.fanout, &self.oids, sha) else {
+            return Ok(None);
+        };
+        let row = &self.commits[i * CDAT_LEN..(i + 1) * CDAT_LEN];
+        let tree = ObjectId::from_bytes(&row[..ObjectId::LEN])?;
+
+        let mut parents = Vec::new();
+        let first = be32(row, ObjectId::LEN);
+        if first != PARENT_NONE {
+            parents.push(self.id(first)?);
+        }
+        let second = be32(row, ObjectId::LEN + 4);
+        if second & EXTRA_EDGES != 0 {
+            // 最後の親には EXTRA_EDGES の bit が立っている
+            let mut j = (second & !EXTRA_EDGES) as usize;
+            loop {
+                if (j + 1) * 4 > self.edges.len() {
+                    return Err(Error::corrupt("commit-graph edge list is out of range"));
+                }
+                let edge = be32(&self.edges, j * 4);
+                parents.push(self.id(edge & !EXTRA_EDGES)?);
+                if edge & EXTRA_EDGES != 0 {
+                    break;
+                }
+                j += 1;
+            }
+        } else if second != PARENT_NONE {
+            parents.push(self.id(second)?);
+        }
+
+        let high = be32(row, ObjectId::LEN + 8);
+        let low = be32(row, ObjectId::LEN + 12);
+        Ok(Some(GraphCommit {
+            tree,
+            parents,
+            generation: high >> 2,
+            commit_time: ((high & 3) as i64) << 32 | low as i64,
+        }))
+    }
+}
+
+// commit-graph に書く 1 commit 分
+pub struct CommitInfo {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    pub commit_time: i64,
+    // 前の commit-graph からわかっているもの
+    pub generation: Option<u32>,
+    // BloomSettings::default() で作った changed-path Bloom filter
+    pub bloom: Option<Vec<u8>>,
+}
+
+// roots からたどれる commit を集める (tag は剥がし、tree や blob を指す ref は飛ばす)
+// 前の commit-graph にある commit は読まずにそこから取る
+pub fn collect_commits(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    roots: impl IntoIterator<Item = ObjectId>,
+) -> Result<BTreeMap<ObjectId, CommitInfo>> {
+    let mut commits = BTreeMap::new();
+    let mut stack = roots.into_iter().collect::<Vec<_>>();
+    while let Some(sha) = stack.pop() {
+        if commits.contains_key(&sha) {
+            continue;
+        }
+        let from_graph = match graph {
+            Some(g) => g.commit(&sha)?.map(|commit| (g, commit)),
+            None => None,
+        };
+        if let Some((g, commit)) = from_graph {
+            stack.extend(commit.parents.iter().copied());
+            // 設定が違う filter は作り直す
+            let bloom = (g.bloom_settings() == Some(&BloomSettings::default()))
+                .then(|| g.bloom_filter(&sha).map(|f| f.to_vec()))
+                .flatten();
+            commits.insert(
+                sha,
+                CommitInfo {
+                    tree: commit.tree,
+                    parents: commit.parents,
+                    commit_time: commit.commit_time,
+                    generation: Some(commit.generation),
+                    bloom,
+                },
+            );
+            continue;
+        }
+        let reader = odb.open(&sha)?;
+        match reader.kind {
+            GitObjectKind::Commit => {}
+            GitObjectKind::Tag => {
+                let data = reader.read_to_vec().map_err(|e| e.with_sha(&sha))?;
+                stack.extend(links(&sha, &data)?);
+                continue;
+            }
+            _ => continue,
+        }
+        let GitObject::Commit {
+            tree,
+            parent,
+            committer,
+            ..
+        } = odb.read(&sha)?
+        else {
+            unreachable!()
+        };
+        stack.extend(parent.iter().copied());
+        commits.insert(
+            sha,
+            CommitInfo {
+                tree,
+                parents: parent,
+                commit_time: committer.timestamp(),
+                generation: None,
+                bloom: None,
+            },
+        );
+    }
+    Ok(commits)
+}
+
+// まだ Bloom filter のない commit について、最初の親の tree との差分から作る
+pub fn compute_bloom_filters(
+    odb: &dyn ObjectDatabase,
+    commits: &mut BTreeMap<ObjectId, CommitInfo>,
+) -> Result<usize> {
+    let settings = BloomSettings::default();
+    let pending = commits
+        .iter()
+        .filter(|(_, commit)| commit.bloom.is_none())
+        .map(|(sha, commit)| {
+            let parent_tree = match commit.parents.first() {
+                Some(p) => Some(
+                    commits
+                        .get(p)
+                        .ok_or(Error::ObjectNotFound(p.to_hex()))?
+                        .tree,
+                ),
+                None => None,
+            };
+            Ok((*sha, parent_tree, commit.tree))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (sha, parent_tree, tree) in &pending {
+        let paths = changed_paths(odb, parent_tree.as_ref(), tree)?;
+        commits.get_mut(sha).unwrap().bloom = Some(settings.filter(paths.as_ref()));
+    }
+    Ok(pending.len())
+}
+
+// 親から順に世代番号を決める (再帰せずにスタックで深さ優先にたどる)
+fn generations(commits: &BTreeMap<ObjectId, CommitInfo>) -> Result<HashMap<ObjectId, u32>> {
+    let mut generations = commits
+        .iter()
+        .filter_map(|(sha, commit)| commit.generation.map(|g| (*sha, g)))
+        .collect::<HashMap<_, _>>();
+    for sha in commits.keys() {
+        let mut stack = vec![*sha];
+        while let Some(top) = stack.last().copied() {
+            if generations.contains_key(&top) {
+                stack.pop();
+                continue;
+            }
+            let parents = &commits
+                .get(&top)
+                .ok_or(Error::ObjectNotFound(top.to_hex()))?
+                .parents;
+            let pending = parents
+                .iter()
+                .filter(|p| !generations.contains_key(*p))
+                .copied()
+                .collect::<Vec<_>>();
+            if pending.is_empty() {
+                let generation = parents.iter().map(|p| generations[p]).max().unwrap_or(0);
+                generations.insert(top, (generation + 1).min(GENERATION_MAX));
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+    Ok(generations)
+}
+
+pub fn serialize_commit_graph(commits: &BTreeMap<ObjectId, CommitInfo>) -> Result<Vec<u8>> {
+    let ids = commits.keys().copied().collect::<Vec<_>>();
+    let position = |sha: &ObjectId| {
+        ids.binary_search(sha)
+            .map(|i| i as u32)
+            .map_err(|_| Error::ObjectNotFound(sha.to_hex()))
+    };
+    let generations = generations(commits)?;
+
+    let mut cdat = Vec::with_capacity(ids.len() * CDAT_LEN);
+    let mut edges = Vec::new();
+    for (sha, commit) in commits {
+        cdat.extend_from_slice(commit.tree.as_bytes());
+        let first = match commit.parents.first() {
+            Some(p) => position(p)?,
+            None => PARENT_NONE,
+        };
+        let second = match commit.parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => position(&commit.parents[1])?,
+            _ => {
+                let start = (edges.len() / 4) as u32 | EXTRA_EDGES;
+                let rest = &commit.parents[1..];
+                for (i, p) in rest.iter().enumerate() {
+                    let mut edge = position(p)?;
+                    if i == rest.len() - 1 {
+                        edge |= EXTRA_EDGES;
+                    }
+                    edges.extend_from_slice(&edge.to_be_bytes());
+                }
+                start
+            }
+        };
+        cdat.extend_from_slice(&first.to_be_bytes());
+        cdat.extend_from_slice(&second.to_be_bytes());
+        let time = commit.commit_time.clamp(0, COMMIT_TIME_MAX);
+        let high = (generations[sha] << 2) | (time >> 32) as u32;
+        cdat.extend_from_slice(&high.to_be_bytes());
+        cdat.extend_from_slice(&(time as u32).to_be_bytes());
+    }
+
+    let mut chunks = vec![
+        (OIDF, fanout_chunk(&ids)),
+        (OIDL, ids.iter().flat_map(|id| *id.as_bytes()).collect()),
+        (CDAT, cdat),
+    ];
+    if !edges.is_empty() {
+        chunks.push((EDGE, edges));
+    }
+    // 一部の commit にしか filter がないときは書かない
+    if commits.values().all(|c| c.bloom.is_some()) {
+        let mut index = Vec::with_capacity(ids.len() * 4);
+        let mut data = BloomSettings::default().to_bytes();
+        for commit in commits.values() {
+            data.extend_from_slice(commit.bloom.as_ref().unwrap());
+            let end = (data.len() - BDAT_HEADER_LEN) as u32;
+            index.extend_from_slice(&end.to_be_bytes());
+        }
+        chunks.push((BIDX, index));
+        chunks.push((BDAT, data));
+    }
+
+    let mut w = HashWriter::new(Vec::new());
+    w.write_all(SIGNATURE)?;
+    // version 1, SHA-1, chunk の数, base graph の数
+    w.write_all(&[1, 1, chunks.len() as u8, 0])?;
+    write_chunks(&mut w, HEADER_LEN, &chunks)?;
+    let (mut data, checksum) = w.finish();
+    data.extend_from_slice(checksum.as_bytes());
+    Ok(data)
+}
+
+pub fn cmd_commit_graph_write() -> anyhow::Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    let mut commits = collect_commits(repo.odb(), repo.commit_graph(), ref_roots(&repo.gitdir)?)?;
+    compute_bloom_filters(repo.odb(), &mut commits)?;
+    let data = serialize_commit_graph(&commits)?;
+
+    let path = CommitGraph::path(&repo.objects_dir);
+    let info_dir = path.parent().unwrap();
+    fs::create_dir_all(info_dir)?;
+    let tmp = tmp_path(info_dir, "tmp_graph");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &path)?;
+    println!("Wrote commit-graph with {} commits", commits.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serialize_commit_graph, CommitGraph, CommitInfo};
+    use crate::object_id::ObjectId;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn round_trip() {
+        let id = |n: u8| ObjectId::from_bytes(&[n; 20]).unwrap();
+        let tree = id(0xee);
+        let mut commits = BTreeMap::new();
+        // 1 <- 2 <- 3, 1 <- 4, octopus 5 = (3, 4, 2)
+        for (n, parents) in [
+            (1, vec![]),
+            (2, vec![id(1)]),
+            (3, vec![id(2)]),
+            (4, vec![id(1)]),
+            (5, vec![id(3), id(4), id(2)]),
+        ] {
+            commits.insert(
+                id(n),
+                CommitInfo {
+                    tree,
+                    parents,
+                    commit_time: 1_700_000_000 + n as i64,
+                    generation: None,
+                    bloom: Some(vec![n; n as usize]),
+                },
+            );
+        }
+        let graph = CommitGraph::parse(&serialize_commit_graph(&commits).unwrap()).unwrap();
+        assert_eq!(graph.len(), 5);
+
+        let octopus = graph.commit(&id(5)).unwrap().unwrap();
+        assert_eq!(octopus.parents, vec![id(3), id(4), id(2)]);
+        assert_eq!(octopus.generation, 4);
+        assert_eq!(octopus.commit_time, 1_700_000_005);
+        assert_eq!(octopus.tree, tree);
+        assert_eq!(graph.commit(&id(4)).unwrap().unwrap().generation, 2);
+        assert!(graph.commit(&id(1)).unwrap().unwrap().parents.is_empty());
+        assert!(graph.commit(&id(9)).unwrap().is_none());
+        assert_eq!(graph.bloom_filter(&id(1)), Some(&[1][..]));
+        assert_eq!(graph.bloom_filter(&id(3)), Some(&[3, 3, 3][..]));
+        assert_eq!(graph.bloom_filter(&id(9)), None);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    commit_graph::collect_commits,
+    git_object::{tree_read, GitObjectKind},
+    git_repository::{repo_find, GitRepository},
+    ls_tree::tree_walk,
+    multi_pack_index::MultiPackIndex,
+    object_database::{LooseObjectDatabase, ObjectDatabase, PackedObjectDatabase},
+    object_id::ObjectId,
+    pack::{pack_list, Pack},
+    reachable::ref_roots,
+};
+use anyhow::Result;
+use bstr::BString;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+// objects/pack に置かれるファイル (これ以外は garbage)
+const PACK_EXTENSIONS: [&str; 6] = ["pack", "idx", "keep", "bitmap", "rev", "promisor"];
+
+pub fn cmd_count_objects(verbose: bool, sizer: bool, top: usize) -> Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    if sizer {
+        return print_sizes(&repo, top);
+    }
+
+    let loose = LooseObjectDatabase::new(repo.objects_dir.clone());
+    let loose_ids = loose.iter()?.collect::<Vec<_>>();
+    let mut loose_size = 0;
+    for sha in &loose_ids {
+        loose_size += disk_usage(&repo.objects_dir.join(sha.loose_path()))?;
+    }
+    if !verbose {
+        println!(
+            "{} objects, {} kilobytes",
+            loose_ids.len(),
+            loose_size / 1024
+        );
+        return Ok(());
+    }
+
+    let pack_dir = repo.objects_dir.join("pack");
+    let idx_paths = pack_list(&pack_dir)?;
+    let mut in_pack = 0;
+    let mut pack_size = 0;
+    for idx_path in &idx_paths {
+        in_pack += Pack::open(idx_path)?.index.len();
+        pack_size += fs::metadata(idx_path)?.len();
+        pack_size += fs::metadata(idx_path.with_extension("pack"))?.len();
+    }
+    // pack にもある loose object は prune-packed で消せる
+    let packed = PackedObjectDatabase::new(pack_dir.clone());
+    let packable = loose_ids.iter().filter(|sha| packed.contains(sha)).count();
+    let garbage = garbage_files(&pack_dir)?;
+    let mut garbage_size = 0;
+    for path in &garbage {
+        garbage_size += fs::metadata(path)?.len();
+    }
+
+    println!("count: {}", loose_ids.len());
+    println!("size: {}", loose_size / 1024);
+    println!("in-pack: {}", in_pack);
+    println!("packs: {}", idx_paths.len());
+    println!("size-pack: {}", pack_size / 1024);
+    println!("prune-packable: {}", packable);
+    println!("garbage: {}", garbage.len());
+    println!("size-garbage: {}", garbage_size / 1024);
+    for dir in &repo.alternates {
+        println!("alternate: {}", dir.display());
+    }
+    Ok(())
+}
+
+// loose object は git と同じく実際に使っているディスクの大きさで数える
+#[cfg(unix)]
+fn disk_usage(path: &Path) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path)?.blocks() * 512)
+}
+
+#[cfg(not(unix))]
+fn disk_usage(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)?.len())
+}
+
+fn garbage_files(pack_dir: &Path) -> Result<Vec<std::path::PathBuf>> {
+    let mut garbage = Vec::new();
+    if !pack_dir.is_dir() {
+        return Ok(garbage);
+    }
+    for entry in fs::read_dir(pack_dir)? {
+        let path = entry?.path();
+        let known = path
+            .extension()
+            .is_some_and(|ext| PACK_EXTENSIONS.iter().any(|e| ext == *e));
+        if path.is_file() && !known && path != MultiPackIndex::path(pack_dir) {
+            garbage.push(path);
+        }
+    }
+    Ok(garbage)
+}
+
+// ref からたどれる history の中で、大きさの原因になりそうなものを順に並べる
+fn print_sizes(repo: &GitRepository, top: usize) -> Result<()> {
+    let odb = repo.odb();
+    let commits = collect_commits(odb, repo.commit_graph(), ref_roots(&repo.gitdir)?)?;
+
+    // 同じ tree や blob は最初に見つけたパスで数える
+    let mut trees: HashSet<ObjectId> = HashSet::new();
+    // (深さ, エントリ数, sha, パス)
+    let mut tree_sizes = Vec::new();
+    let mut blobs: HashMap<ObjectId, (usize, BString)> = HashMap::new();
+    for info in commits.values() {
+        if !trees.insert(info.tree) {
+            continue;
+        }
+        let width = tree_read(odb, &info.tree)?.len();
+        tree_sizes.push((0, width, info.tree, BString::from("/")));
+        tree_walk(odb, &info.tree, &BString::from(""), &mut |o, path| {
+            match o.file_type.kind() {
+                GitObjectKind::Tree => {
+                    if !trees.insert(o.sha) {
+                        return Ok(false);
+                    }
+                    let depth = path.iter().filter(|c| **c == b'/').count() + 1;
+                    // 中をたどるときにもう一度読むがキャッシュに入っている
+                    let width = tree_read(odb, &o.sha)?.len();
+                    tree_sizes.push((depth, width, o.sha, path.clone()));
+                    Ok(true)
+                }
+                GitObjectKind::Blob => {
+                    if let Entry::Vacant(e) = blobs.entry(o.sha) {
+                        e.insert((odb.open(&o.sha)?.size, path.clone()));
+                    }
+                    Ok(false)
+                }
+                // submodule の commit は読まない
+                _ => Ok(false),
+            }
+        })?;
+    }
+
+    let blob_bytes = blobs.values().map(|(size, _)| size).sum::<usize>();
+    println!(
+        "{} commits, {} trees, {} blobs ({} bytes)",
+        commits.len(),
+        trees.len(),
+        blobs.len(),
+        blob_bytes
+    );
+
+    let mut blobs = blobs.into_iter().collect::<Vec<_>>();
+    blobs.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(&b.0)));
+    println!("\nLargest blobs:");
+    for (sha, (size, path)) in blobs.iter().take(top) {
+        println!("{:>12} {} {}", size, sha, path);
+    }
+
+    tree_sizes.sort_by(|a, b| b.0.cmp(&a.0).then(a.2.cmp(&b.2)));
+    println!("\nDeepest trees:");
+    for (depth, _, sha, path) in tree_sizes.iter().take(top) {
+        println!("{:>12} {} {}", depth, sha, path);
+    }
+
+    tree_sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+    println!("\nWidest trees:");
+    for (_, width, sha, path) in tree_sizes.iter().take(top) {
+        println!("{:>12} {} {}", width, sha, path);
+    }
+
+    let mut merges = commits
+        .iter()
+        .map(|(sha, info)| (info.parents.len(), sha))
+        .collect::<Vec<_>>();
+    merges.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    println!("\nCommits with the most parents:");
+    for (parents, sha) in merges.iter().take(top) {
+        println!("{:>12} {}", parents, sha);
+    }
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use crate::{
+    error::{Error, Result},
+    object_stream::MAX_PREALLOC,
+};
+use std::collections::HashMap;
+
+// base を BLOCK byte ごとに区切って覚えておき、target の中で同じ並びを探す
+const BLOCK: usize = 16;
+// 1 つの copy 命令のサイズ (0 と書くと 0x10000 として読まれる)
+const MAX_COPY: usize = 0x10000;
+const MAX_INSERT: usize = 0x7f;
+// 空行の連続など同じ block がたくさんあるときに調べる候補の数
+const MAX_CANDIDATES: usize = 16;
+
+// delta のヘッダにあるサイズ (7 bit ずつの little endian)
+fn read_size(delta: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let b = *delta
+            .get(*pos)
+            .ok_or(Error::corrupt("Truncated delta header"))?;
+        *pos += 1;
+        if shift > 56 {
+            return Err(Error::corrupt("Delta size too large"));
+        }
+        size |= ((b & 0x7f) as usize) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+// git の delta (copy/insert 命令の列) を base に適用する
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let base_size = read_size(delta, &mut pos)?;
+    let result_size = read_size(delta, &mut pos)?;
+    if base_size != base.len() {
+        return Err(Error::corrupt(format!(
+            "Delta base size mismatch: expected {}, got {}",
+            base_size,
+            base.len()
+        )));
+    }
+
+    let truncated = || Error::corrupt("Truncated delta");
+    let mut result = Vec::with_capacity(result_size.min(MAX_PREALLOC));
+    while pos < delta.len() {
+        let cmd = delta[pos];
+        pos += 1;
+        if cmd & 0x80 != 0 {
+            // copy: 下位 4 bit が offset、続く 3 bit が size のどの byte があるか
+            let mut offset = 0usize;
+            for i in 0..4 {
+                if cmd & (1 << i) != 0 {
+                    offset |= (*delta.get(pos).ok_or_else(truncated)? as usize) << (8 * i);
+                    pos += 1;
+                }
+            }
+            let mut size = 0usize;
+            for i in 0..3 {
+                if cmd & (0x10 << i) != 0 {
+                    size |= (*delta.get(pos).ok_or_else(truncated)? as usize) << (8 * i);
+                    pos += 1;
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let chunk = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or(Error::corrupt("Delta copy out of range"))?;
+            result.extend_from_slice(chunk);
+        } else if cmd != 0 {
+            // insert: cmd byte をそのまま追加する
+            let chunk = delta.get(pos..pos + cmd as usize).ok_or_else(truncated)?;
+            result.extend_from_slice(chunk);
+            pos += cmd as usize;
+        } else {
+            return Err(Error::corrupt("Invalid delta opcode 0"));
+        }
+    }
+
+    if result.len() != result_size {
+        return Err(Error::corrupt(format!(
+            "Delta result size mismatch: expected {}, got {}",
+            result_size,
+            result.len()
+        )));
+    }
+    Ok(result)
+}
+
+fn write_size(out: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        out.push(0x80 | (size & 0x7f) as u8);
+        size >>= 7;
+    }
+    out.push(size as u8);
+}
+
+fn write_insert(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+// offset, size のうち 0 でない byte だけを書き、どれを書いたかを cmd の bit で表す
+fn write_copy(out: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+        let cmd_pos = out.len();
+        let mut cmd = 0x80u8;
+        out.push(0);
+        for i in 0..4 {
+            let b = (offset >> (8 * i)) as u8;
+            if b != 0 {
+                cmd |= 1 << i;
+                out.push(b);
+            }
+        }
+        if size != MAX_COPY {
+            for i in 0..3 {
+                let b = (size >> (8 * i)) as u8;
+                if b != 0 {
+                    cmd |= 0x10 << i;
+                    out.push(b);
+                }
+            }
+        }
+        out[cmd_pos] = cmd;
+        offset += size;
+        len -= size;
+    }
+}
+
+// 1 つの base から複数の target への delta を作るため、base の索引を使い回す
+pub struct DeltaIndex {
+    base: Vec<u8>,
+    blocks: HashMap<[u8; BLOCK], Vec<usize>>,
+}
+
+impl DeltaIndex {
+    pub fn new(base: Vec<u8>) -> Self {
+        let mut blocks: HashMap<[u8; BLOCK], Vec<usize>> = HashMap::new();
+        for (i, chunk) in base.chunks_exact(BLOCK).enumerate() {
+            let positions = blocks.entry(chunk.try_into().unwrap()).or_default();
+            if positions.len() < MAX_CANDIDATES {
+                positions.push(i * BLOCK);
+            }
+        }
+        Self { base, blocks }
+    }
+
+    pub fn base(&self) -> &[u8] {
+        &self.base
+    }
+
+    // target を copy/insert 命令で表す。max_size より大きくなるなら None
+    pub fn delta(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        write_size(&mut out, self.base.len());
+        write_size(&mut out, target.len());
+
+        // target[insert_start..pos] はまだ書いていない insert
+        let mut insert_start = 0;
+        let mut pos = 0;
+        while pos + BLOCK <= target.len() {
+            let key: [u8; BLOCK] = target[pos..pos + BLOCK].try_into().unwrap();
+            let found = self.blocks.get(&key).and_then(|candidates| {
+                candidates
+                    .iter()
+                    .map(|&start| {
+                        // 一致した block から前後に伸ばす
+                        let forward = self.base[start..]
+                            .iter()
+                            .zip(&target[pos..])
+                            .take_while(|(a, b)| a == b)
+                            .count();
+                        let backward = self.base[..start]
+                            .iter()
+                            .rev()
+                            .zip(target[insert_start..pos].iter().rev())
+                            .take_while(|(a, b)| a == b)
+                            .count();
+                        (start - backward, pos - backward, forward + backward)
+                    })
+                    .max_by_key(|&(_, _, len)| len)
+            });
+            let Some((base_start, target_start, len)) = found else {
+                pos += 1;
+                if out.len() + pos - insert_start > max_size {
+                    return None;
+                }
+                continue;
+            };
+            write_insert(&mut out, &target[insert_start..target_start]);
+            write_copy(&mut out, base_start, len);
+            pos = target_start + len;
+            insert_start = pos;
+            if out.len() > max_size {
+                return None;
+            }
+        }
+        write_insert(&mut out, &target[insert_start..]);
+        (out.len() <= max_size).then_some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, DeltaIndex};
+
+    #[test]
+    fn apply() {
+        // "hello " を base からコピーして "there" を追加する
+        let delta = [11, 11, 0x90, 6, 5, b't', b'h', b'e', b'r', b'e'];
+        assert_eq!(apply_delta(b"hello world", &delta).unwrap(), b"hello there");
+
+        // base のサイズ違い、範囲外のコピー、途中で切れた insert
+        assert!(apply_delta(b"hello", &delta).is_err());
+        assert!(apply_delta(b"hello world", &[11, 4, 0x91, 10, 4]).is_err());
+        assert!(apply_delta(b"hello world", &[11, 5, 5, b'a']).is_err());
+    }
+
+    #[test]
+    fn create() {
+        let base = (0..3000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<Vec<_>>()
+            .concat();
+        let target = base.replace("line 1500\n", "changed\n") + "appended\n";
+        let index = DeltaIndex::new(base.clone().into_bytes());
+        let delta = index.delta(target.as_bytes(), usize::MAX).unwrap();
+        assert!(delta.len() < 100);
+        assert_eq!(
+            apply_delta(base.as_bytes(), &delta).unwrap(),
+            target.as_bytes()
+        );
+
+        // 0x10000 を超える copy と、似ていない target
+        let big = b"0123456789abcdef".repeat(10000);
+        let delta = DeltaIndex::new(big.clone())
+            .delta(&big, usize::MAX)
+            .unwrap();
+        assert_eq!(apply_delta(&big, &delta).unwrap(), big);
+        assert!(index.delta(&big, 1000).is_none());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::object_id::ObjectId;
+use std::{fmt, io, path::PathBuf};
+
+// ライブラリ部分 (オブジェクトやリポジトリの読み書き) のエラー
+// コマンド側は anyhow で受けるが、種類で分岐したいときは downcast して match できる
+#[derive(Debug)]
+pub enum Error {
+    NotARepository(PathBuf),
+    InvalidObjectName(String),
+    ObjectNotFound(String),
+    // 短縮 SHA-1 に一致するものが複数ある ("<sha> <kind>" の一覧)
+    AmbiguousObjectName {
+        name: String,
+        candidates: Vec<String>,
+    },
+    // 中身が壊れている、もしくは形式が正しくない
+    CorruptObject {
+        sha: Option<ObjectId>,
+        reason: String,
+    },
+    // tag や commit を目的の種類まで剥がせない
+    UnexpectedObjectKind {
+        sha: ObjectId,
+        expected: String,
+    },
+    InvalidRef(String),
+    Io(io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn corrupt(reason: impl Into<String>) -> Self {
+        Error::CorruptObject {
+            sha: None,
+            reason: reason.into(),
+        }
+    }
+
+    // どのオブジェクトが壊れていたのかを付け加える
+    pub fn with_sha(self, sha: &ObjectId) -> Self {
+        match self {
+            Error::CorruptObject { sha: None, reason } => Error::CorruptObject {
+                sha: Some(*sha),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotARepository(path) => write!(f, "not a git repository: {}", path.display()),
+            Error::InvalidObjectName(name) => write!(f, "Invalid object name {}", name),
+            Error::ObjectNotFound(name) => write!(f, "object {} not found", name),
+            Error::AmbiguousObjectName { name, candidates } => write!(
+                f,
+                "ambiguous short id {}\nThe candidates are:\n  {}",
+                name,
+                candidates.join("\n  ")
+            ),
+            Error::CorruptObject {
+                sha: Some(sha),
+                reason,
+            } => write!(f, "corrupt object {}: {}", sha, reason),
+            Error::CorruptObject { sha: None, reason } => write!(f, "corrupt object: {}", reason),
+            Error::UnexpectedObjectKind { sha, expected } => {
+                write!(f, "{} can not be peeled to {}", sha, expected)
+            }
+            Error::InvalidRef(name) => write!(f, "invalid ref {}", name),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Display に io::Error をそのまま出しているので、同じ文を原因としてもう一度出さない
+        match self {
+            Error::Io(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,157 @@
+use crate::error::{Error, Result};
+
+// EWAH: 全部 0 か全部 1 の word の連続を 1 つの marker word にまとめる圧縮
+// marker word は下から running bit (1 bit), 連続の長さ (32 bit), 続く literal word の数 (31 bit)
+const RUNNING_LEN_MAX: u64 = (1 << 32) - 1;
+const LITERAL_LEN_MAX: u64 = (1 << 31) - 1;
+
+// 展開した bitmap (bit i は words[i / 64] の下から i % 64 番目)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, i: usize) {
+        if self.words.len() <= i / 64 {
+            self.words.resize(i / 64 + 1, 0);
+        }
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words
+            .get(i / 64)
+            .is_some_and(|w| w & (1 << (i % 64)) != 0)
+    }
+
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            *w |= o;
+        }
+    }
+
+    pub fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            *w ^= o;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    // bit の数, word の数, word の列, 最後の marker word の位置 (すべて big endian)
+    pub fn write_ewah(&self, out: &mut Vec<u8>) {
+        let mut ewah = Vec::new();
+        let mut last_marker = 0;
+        let mut i = 0;
+        // 空の bitmap も marker word を 1 つ持つ
+        while i < self.words.len() || ewah.is_empty() {
+            last_marker = ewah.len();
+            ewah.push(0);
+            let mut marker = 0u64;
+            if let Some(&w) = self.words.get(i).filter(|w| **w == 0 || **w == !0) {
+                let mut run = 0;
+                while i < self.words.len() && self.words[i] == w && run < RUNNING_LEN_MAX {
+                    run += 1;
+                    i += 1;
+                }
+                marker = (w & 1) | run << 1;
+            }
+            let mut literals = 0;
+            while i < self.words.len()
+                && self.words[i] != 0
+                && self.words[i] != !0
+                && literals < LITERAL_LEN_MAX
+            {
+                ewah.push(self.words[i]);
+                literals += 1;
+                i += 1;
+            }
+            ewah[last_marker] = marker | literals << 33;
+        }
+
+        out.extend_from_slice(&((self.words.len() * 64) as u32).to_be_bytes());
+        out.extend_from_slice(&(ewah.len() as u32).to_be_bytes());
+        for w in &ewah {
+            out.extend_from_slice(&w.to_be_bytes());
+        }
+        out.extend_from_slice(&(last_marker as u32).to_be_bytes());
+    }
+
+    pub fn read_ewah(data: &[u8], pos: &mut usize) -> Result<Self> {
+        let truncated = || Error::corrupt("Truncated EWAH bitmap");
+        let mut take = |len: usize| -> Result<&[u8]> {
+            let bytes = data.get(*pos..*pos + len).ok_or_else(truncated)?;
+            *pos += len;
+            Ok(bytes)
+        };
+        let bit_size = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+        let len = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+        let ewah = take(len * 8)?
+            .chunks_exact(8)
+            .map(|w| u64::from_be_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        take(4)?;
+
+        let mut words = Vec::new();
+        let mut i = 0;
+        while i < ewah.len() {
+            let marker = ewah[i];
+            i += 1;
+            let run = (marker >> 1) & RUNNING_LEN_MAX;
+            let literals = (marker >> 33) as usize;
+            let fill = if marker & 1 != 0 { !0 } else { 0 };
+            // 壊れた marker で巨大な領域を確保しないように
+            if words.len() + run as usize > bit_size.div_ceil(64) {
+                return Err(Error::corrupt("EWAH bitmap is longer than its size"));
+            }
+            words.resize(words.len() + run as usize, fill);
+            let chunk = ewah.get(i..i + literals).ok_or_else(truncated)?;
+            words.extend_from_slice(chunk);
+            i += literals;
+        }
+        Ok(Self { words })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitmap;
+
+    #[test]
+    fn ewah_round_trip() {
+        let mut bitmap = Bitmap::new();
+        // 0 の連続、1 の連続、literal が混ざるように
+        for i in (0..64 * 3).chain(1000..1003).chain(64 * 40..64 * 45) {
+            bitmap.set(i);
+        }
+        let mut data = Vec::new();
+        bitmap.write_ewah(&mut data);
+        let mut pos = 0;
+        let read = Bitmap::read_ewah(&data, &mut pos).unwrap();
+        assert_eq!(pos, data.len());
+        assert_eq!(read, bitmap);
+        assert_eq!(read.count(), 64 * 3 + 3 + 64 * 5);
+        assert!(read.get(1001) && !read.get(1003));
+
+        // 1 が 3 word / 0 が 12 word と literal 1 word / 0 が 24 word / 1 が 5 word
+        assert_eq!(data.len() - 12, 8 * 5);
+        assert!(Bitmap::read_ewah(&data[..data.len() - 1], &mut 0).is_err());
+
+        let mut empty = Vec::new();
+        Bitmap::new().write_ewah(&mut empty);
+        assert_eq!(Bitmap::read_ewah(&empty, &mut 0).unwrap(), Bitmap::new());
+    }
+}
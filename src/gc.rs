@@ -0,0 +1,19 @@
+use crate::{
+    pack::DeltaOptions,
+    prune::cmd_prune,
+    repack::{cmd_repack, RepackOptions},
+};
+use anyhow::Result;
+
+// 到達できる loose object を pack にまとめてから、残った到達できないものを猶予期間付きで消す
+// pack 済みのものは消さない (-a -d と違い、pack の中の到達できないものも残る)
+pub fn cmd_gc(prune: String) -> Result<()> {
+    cmd_repack(RepackOptions {
+        all: false,
+        delete: true,
+        write_bitmap: false,
+        delta: DeltaOptions::default(),
+    })?;
+    // repack で作った pack を読むため、リポジトリは開き直す
+    cmd_prune(Some(prune), false)
+}
@@ -1,22 +1,15 @@
+use crate::signature::Signature;
 use anyhow::Result;
 use ini::Ini;
 use std::path::PathBuf;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct GitConfig {
     pub repository_format_version: i32,
     pub filemode: bool,
     pub bare: bool,
-}
-
-impl Default for GitConfig {
-    fn default() -> Self {
-        Self {
-            repository_format_version: 0,
-            filemode: false,
-            bare: false,
-        }
-    }
+    pub user_name: Option<String>,
+    pub user_email: Option<String>,
 }
 
 impl GitConfig {
@@ -28,13 +21,29 @@ impl GitConfig {
             .get("repositoryformatversion")
             .ok_or(anyhow::anyhow!("repositoryformatversion not found"))?
             .parse::<i32>()?;
+        let user = conf.section(Some("user"));
         Ok(Self {
             repository_format_version: core_repository_format_version,
             filemode: false,
             bare: false,
+            user_name: user.and_then(|s| s.get("name")).map(|s| s.to_string()),
+            user_email: user.and_then(|s| s.get("email")).map(|s| s.to_string()),
         })
     }
 
+    // 環境変数 > .git/config の [user] の順で名前とメールアドレスを決める
+    pub fn signature(&self, name_env: &str, email_env: &str) -> Result<Signature> {
+        let name = std::env::var(name_env)
+            .ok()
+            .or(self.user_name.clone())
+            .ok_or(anyhow::anyhow!("user.name is not set"))?;
+        let email = std::env::var(email_env)
+            .ok()
+            .or(self.user_email.clone())
+            .ok_or(anyhow::anyhow!("user.email is not set"))?;
+        Ok(Signature::now(name.into(), email.into()))
+    }
+
     pub fn write(&self, path: &PathBuf) -> Result<()> {
         let mut conf = Ini::new();
         conf.with_section(Some("core")).set(
@@ -45,6 +54,12 @@ impl GitConfig {
             .set("filemode", self.filemode.to_string());
         conf.with_section(Some("core"))
             .set("bare", self.bare.to_string());
+        if let Some(name) = &self.user_name {
+            conf.with_section(Some("user")).set("name", name);
+        }
+        if let Some(email) = &self.user_email {
+            conf.with_section(Some("user")).set("email", email);
+        }
         conf.write_to_file(path)?;
         Ok(())
     }
@@ -1,11 +1,13 @@
-use anyhow::Result;
-use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
-use indexmap::IndexMap;
-use sha1::{Digest, Sha1};
+use crate::{
+    error::{Error, Result},
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+    object_stream::object_hash_stream,
+    signature::Signature,
+};
+use bstr::{BString, ByteSlice};
 use std::{
-    fs::{self, File},
-    io::{Cursor, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{Cursor, Read, Seek, SeekFrom},
     str::FromStr,
 };
 
@@ -18,10 +20,10 @@ pub enum GitObjectKind {
 }
 
 impl FromStr for GitObjectKind {
-    type Err = anyhow::Error;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<GitObjectKind, Self::Err> {
-        GitObjectKind::from_str(s).ok_or(anyhow::anyhow!("Invalid header"))
+        GitObjectKind::from_str(s).ok_or(format!("Invalid object type {}", s))
     }
 }
 
@@ -36,7 +38,7 @@ impl GitObjectKind {
     }
 
     pub fn from_str(s: &str) -> Option<GitObjectKind> {
-        match dbg!(s) {
+        match s {
             "blob" => Some(GitObjectKind::Blob),
             "commit" => Some(GitObjectKind::Commit),
             "tag" => Some(GitObjectKind::Tag),
@@ -44,6 +46,26 @@ impl GitObjectKind {
             _ => None,
         }
     }
+
+    // packfile のエントリヘッダに書かれる型番号
+    pub fn pack_type(&self) -> u8 {
+        match self {
+            GitObjectKind::Commit => 1,
+            GitObjectKind::Tree => 2,
+            GitObjectKind::Blob => 3,
+            GitObjectKind::Tag => 4,
+        }
+    }
+
+    pub fn from_pack_type(n: u8) -> Option<GitObjectKind> {
+        match n {
+            1 => Some(GitObjectKind::Commit),
+            2 => Some(GitObjectKind::Tree),
+            3 => Some(GitObjectKind::Blob),
+            4 => Some(GitObjectKind::Tag),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -52,21 +74,23 @@ pub enum GitObject {
         content: Vec<u8>,
     },
     Commit {
-        // SHA-1 hash
-        tree: String,
+        tree: ObjectId,
         // 2つより多くなるのかわからん
-        parent: Vec<String>,
-        author: String,
-        committer: String,
-        // gpgsig: String,
-        message: String,
+        parent: Vec<ObjectId>,
+        author: Signature,
+        committer: Signature,
+        // gpgsig, encoding, mergetag など committer より後ろのヘッダ (元の順番を保つ)
+        extra_headers: Vec<(BString, BString)>,
+        message: BString,
     },
     Tag {
-        object: String,
+        object: ObjectId,
         kind: GitObjectKind,
-        tag: String,
-        tagger: String,
-        message: String,
+        tag: BString,
+        // 古い tag (git 0.99 以前) には tagger がない
+        tagger: Option<Signature>,
+        extra_headers: Vec<(BString, BString)>,
+        message: BString,
     },
     Tree(Vec<TreeOject>),
 }
@@ -75,8 +99,27 @@ pub enum GitObject {
 pub struct TreeOject {
     pub file_type: FileType,
     pub permission: String,
-    pub path: PathBuf,
-    pub sha: String,
+    pub path: BString,
+    pub sha: ObjectId,
+}
+
+impl TreeOject {
+    // tree に書き込まれる mode (tree は先頭の 0 を付けない)
+    pub fn mode(&self) -> String {
+        match self.file_type {
+            FileType::Tree => "40000".to_string(),
+            _ => format!("{}{}", self.file_type.as_str(), self.permission),
+        }
+    }
+
+    // git は tree を "name/" として並べる
+    fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.path.to_vec();
+        if self.file_type == FileType::Tree {
+            key.push(b'/');
+        }
+        key
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -108,7 +151,7 @@ impl FileType {
 }
 
 impl TryFrom<&[u8]> for FileType {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<FileType> {
         match value {
@@ -116,55 +159,29 @@ impl TryFrom<&[u8]> for FileType {
             b"10" => Ok(FileType::RegularFile),
             b"12" => Ok(FileType::SymbolicLink),
             b"16" => Ok(FileType::Submodule),
-            _ => anyhow::bail!("Invalid file type {:#0x?}", value),
+            _ => Err(Error::corrupt(format!("Invalid file type {:#0x?}", value))),
         }
     }
 }
 
 impl GitObject {
-    fn kind(&self) -> GitObjectKind {
+    pub fn kind(&self) -> GitObjectKind {
         match self {
             GitObject::Blob { .. } => GitObjectKind::Blob,
             GitObject::Commit { .. } => GitObjectKind::Commit,
             GitObject::Tag { .. } => GitObjectKind::Tag,
-            _ => todo!(),
+            GitObject::Tree(_) => GitObjectKind::Tree,
         }
     }
 
-    pub fn hash(&self) -> Result<String> {
+    pub fn hash(&self) -> ObjectId {
         let data = serialize_object(self);
-        let result = [
-            self.kind().as_str().to_string().into_bytes(),
-            vec![b' '],
-            format!("{}", data.len()).into_bytes(),
-            vec![0],
-            data,
-        ]
-        .concat();
-        let sha = Sha1::digest(&result);
-        let sha = hex::encode(sha);
-        Ok(sha)
-    }
-
-    pub fn write(&self, gitdir: &PathBuf) -> Result<()> {
-        let data = serialize_object(self);
-        let result = [
-            self.kind().as_str().to_string().into_bytes(),
-            vec![b' '],
-            format!("{}", data.len()).into_bytes(),
-            vec![0],
-            data,
-        ]
-        .concat();
-        let sha = self.hash()?;
-        let path = gitdir.join("objects").join(&sha[..2]).join(&sha[2..]);
-        fs::create_dir_all(path.parent().unwrap())?;
-        if !path.exists() {
-            let f = File::create(&path)?;
-            let mut zipped = ZlibEncoder::new(f, Compression::default());
-            zipped.write_all(&result)?;
-        }
-        Ok(())
+        // メモリ上のデータなので長さが食い違うことはない
+        object_hash_stream(&self.kind(), data.len(), data.as_slice()).unwrap()
+    }
+
+    pub fn write(&self, odb: &dyn ObjectDatabase) -> Result<ObjectId> {
+        odb.write(self)
     }
 }
 
@@ -176,152 +193,258 @@ pub fn serialize_object(obj: &GitObject) -> Vec<u8> {
             parent,
             author,
             committer: comitter,
+            extra_headers,
             message,
-            ..
         } => {
-            let dct = IndexMap::from_iter(vec![
-                ("tree".to_string(), vec![tree.clone()]),
-                ("parent".to_string(), parent.clone()),
-                ("author".to_string(), vec![author.clone()]),
-                ("committer".to_string(), vec![comitter.clone()]),
-                // (Some("gpgsig".to_string()), vec![gpgsig.clone()]),
-                ("message".to_string(), vec![message.clone()]),
-            ]);
-            serialize_indexmap(dct).unwrap()
+            let mut kvlm = vec![(BString::from("tree"), BString::from(tree.to_hex()))];
+            kvlm.extend(
+                parent
+                    .iter()
+                    .map(|p| (BString::from("parent"), BString::from(p.to_hex()))),
+            );
+            kvlm.push(("author".into(), author.to_bytes().into()));
+            kvlm.push(("committer".into(), comitter.to_bytes().into()));
+            kvlm.extend(extra_headers.iter().cloned());
+            serialize_kvlm(&kvlm, message)
         }
-        GitObject::Tree(_) => tree_serialize(obj).unwrap(),
+        GitObject::Tree(objects) => tree_serialize(objects),
         GitObject::Tag {
             object,
             kind,
             tag,
             tagger,
+            extra_headers,
             message,
-            ..
         } => {
-            let dct = IndexMap::from_iter(vec![
-                ("object".to_string(), vec![object.clone()]),
-                ("type".to_string(), vec![kind.as_str().to_string()]),
-                ("tag".to_string(), vec![tag.clone()]),
-                ("tagger".to_string(), vec![tagger.clone()]),
-                ("message".to_string(), vec![message.clone()]),
-            ]);
-            serialize_indexmap(dct).unwrap()
+            let mut kvlm = vec![
+                (BString::from("object"), BString::from(object.to_hex())),
+                ("type".into(), kind.as_str().into()),
+                ("tag".into(), tag.clone()),
+            ];
+            if let Some(tagger) = tagger {
+                kvlm.push(("tagger".into(), tagger.to_bytes().into()));
+            }
+            kvlm.extend(extra_headers.iter().cloned());
+            serialize_kvlm(&kvlm, message)
         }
     }
 }
 
-pub fn deserialize_object(data: &[u8]) -> GitObject {
-    todo!()
+// kind と ヘッダを取り除いた中身から GitObject を復元する
+pub fn deserialize_object(kind: GitObjectKind, data: &[u8]) -> Result<GitObject> {
+    match kind {
+        GitObjectKind::Blob => Ok(GitObject::Blob {
+            content: data.to_vec(),
+        }),
+        GitObjectKind::Commit => {
+            let mut kvlm = Vec::new();
+            let message = parse_commit(data, 0, &mut kvlm)?;
+            let mut headers = kvlm.into_iter().peekable();
+            let tree = kvlm_next(&mut headers, "tree")?.try_into()?;
+            let mut parent = Vec::new();
+            while let Some((_, p)) = headers.next_if(|(k, _)| k == "parent") {
+                parent.push(p.try_into()?);
+            }
+            Ok(GitObject::Commit {
+                tree,
+                parent,
+                author: Signature::parse(&kvlm_next(&mut headers, "author")?),
+                committer: Signature::parse(&kvlm_next(&mut headers, "committer")?),
+                extra_headers: headers.collect(),
+                message,
+            })
+        }
+        GitObjectKind::Tag => {
+            let mut kvlm = Vec::new();
+            let message = parse_commit(data, 0, &mut kvlm)?;
+            let mut headers = kvlm.into_iter().peekable();
+            let object = kvlm_next(&mut headers, "object")?.try_into()?;
+            let kind = kvlm_next(&mut headers, "type")?;
+            Ok(GitObject::Tag {
+                object,
+                kind: kind
+                    .to_str()
+                    .ok()
+                    .and_then(GitObjectKind::from_str)
+                    .ok_or(Error::corrupt(format!("Invalid tag type {}", kind)))?,
+                tag: kvlm_next(&mut headers, "tag")?,
+                tagger: headers
+                    .next_if(|(k, _)| k == "tagger")
+                    .map(|(_, v)| Signature::parse(&v)),
+                extra_headers: headers.collect(),
+                message,
+            })
+        }
+        GitObjectKind::Tree => tree_parse(data),
+    }
+}
+
+// 決まった順番で並ぶヘッダを 1 つ取り出す
+fn kvlm_next(headers: &mut impl Iterator<Item = (BString, BString)>, key: &str) -> Result<BString> {
+    match headers.next() {
+        Some((k, v)) if k == key => Ok(v),
+        Some((k, _)) => Err(Error::corrupt(format!(
+            "Unexpected header {}, expected {}",
+            k, key
+        ))),
+        None => Err(Error::corrupt(format!("{} field not found", key))),
+    }
 }
 
 // commitをparseする
-pub fn parse_commit<'a>(
+// ヘッダは出てきた順番のまま kvlm に積み、メッセージを返す
+// メッセージやヘッダの値は UTF-8 とは限らないのでバイト列のまま扱う
+pub fn parse_commit(
     data: &[u8],
     start: usize,
-    dct: &mut IndexMap<String, Vec<String>>,
-) -> Result<()> {
-    let spc = data[start..]
-        .iter()
-        .position(|&data| data == b' ')
-        .map(|i| (start + i) as i32)
-        .unwrap_or(-1);
-    let nl = data[start..]
-        .iter()
-        .position(|&data| data == b'\n')
-        .map(|i| (start + i) as i32)
-        .unwrap_or(-1);
-
-    if (spc < 0) || nl < spc {
-        assert!(nl == start as i32);
-        dct.insert(
-            "message".to_string(),
-            vec![String::from_utf8(data[start + 1..].to_vec())?],
-        );
-        return Ok(());
-    }
-    let spc = spc as usize;
+    kvlm: &mut Vec<(BString, BString)>,
+) -> Result<BString> {
+    let spc = data[start..].find_byte(b' ').map(|i| start + i);
+    let nl = data[start..].find_byte(b'\n').map(|i| start + i);
+
+    // 空行以降はメッセージ
+    let spc = match (spc, nl) {
+        (Some(spc), Some(nl)) if spc < nl => spc,
+        (_, Some(nl)) => {
+            if nl != start {
+                return Err(Error::corrupt(format!(
+                    "Malformed header line at {}",
+                    start
+                )));
+            }
+            return Ok(data[start + 1..].into());
+        }
+        _ => return Err(Error::corrupt(format!("Unterminated header at {}", start))),
+    };
 
-    let key = String::from_utf8(data[start..spc].to_vec())?;
+    let key = BString::from(&data[start..spc]);
     let mut end = start;
     loop {
         end = data[end + 1..]
-            .iter()
-            .position(|&data| data == b'\n')
+            .find_byte(b'\n')
             .map(|i| end + 1 + i)
-            .unwrap();
-        // 32: ord(' '), 半角スペースのASCIIコード
-        if data[end + 1] != ' ' as u8 {
+            .ok_or(Error::corrupt(format!("Unterminated header {}", key)))?;
+        // 継続行は半角スペースで始まる
+        if data.get(end + 1) != Some(&b' ') {
             break;
         }
     }
-    let value = String::from_utf8(data[spc + 1..end].to_vec())?.replace("\n ", "\n");
+    let value = data[spc + 1..end].replace(b"\n ", b"\n");
+    kvlm.push((key, value.into()));
+
+    parse_commit(data, end + 1, kvlm)
+}
 
-    if let Some(e) = dct.get_mut(&key) {
-        // 要素があったらpush
-        e.push(value);
-    } else {
-        // なかったらinsert
-        dct.insert(key, vec![value]);
+pub fn serialize_kvlm(kvlm: &[(BString, BString)], message: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for (k, v) in kvlm.iter() {
+        ret.extend_from_slice(k);
+        ret.push(b' ');
+        ret.extend(v.replace(b"\n", b"\n "));
+        ret.push(b'\n');
     }
+    ret.push(b'\n');
+    ret.extend_from_slice(message);
+    ret
+}
 
-    parse_commit(data, end + 1, dct)
+// "<kind> <size>\0" のヘッダを読む
+pub fn parse_header(bin: &[u8]) -> Result<(GitObjectKind, usize, usize)> {
+    let invalid = || Error::corrupt(format!("Invalid header {}", bin.as_bstr()));
+    let space_at = bin.find_byte(b' ').ok_or_else(invalid)?;
+    let null_at = bin
+        .find_byte(0)
+        .filter(|&null_at| space_at < null_at)
+        .ok_or_else(invalid)?;
+    let kind = std::str::from_utf8(&bin[..space_at])
+        .ok()
+        .and_then(GitObjectKind::from_str)
+        .ok_or_else(invalid)?;
+    let size: usize = std::str::from_utf8(&bin[space_at + 1..null_at])
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((kind, size, null_at + 1))
 }
 
-pub fn serialize_indexmap(kvml: IndexMap<String, Vec<String>>) -> Result<Vec<u8>> {
-    let mut ret = String::new();
-    for (k, val) in kvml.iter() {
-        if k == "message" {
-            continue;
+// 短縮された SHA-1 (4 文字以上) も受け付けて ObjectId に解決する
+pub fn object_resolve(odb: &dyn ObjectDatabase, name: &str) -> Result<ObjectId> {
+    if !(4..=ObjectId::LEN * 2).contains(&name.len())
+        || !name.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(Error::InvalidObjectName(name.to_string()));
+    }
+    let name = name.to_ascii_lowercase();
+    if name.len() == ObjectId::LEN * 2 {
+        let sha = name.parse()?;
+        if !odb.contains(&sha) {
+            return Err(Error::ObjectNotFound(name));
         }
-        for v in val.iter() {
-            ret += format!("{} {}\n", k, v.replace("\n", "\n ")).as_str();
+        return Ok(sha);
+    }
+
+    let mut candidates = odb.find_prefix(&name)?;
+    candidates.sort();
+
+    match candidates.as_slice() {
+        [] => Err(Error::ObjectNotFound(name)),
+        [id] => Ok(*id),
+        _ => {
+            let candidates = candidates
+                .iter()
+                .map(|id| {
+                    // 本体は展開せずにヘッダだけ読む
+                    let kind = odb
+                        .open(id)
+                        .map(|reader| reader.kind.as_str().to_string())
+                        .unwrap_or("unknown".to_string());
+                    format!("{} {}", id, kind)
+                })
+                .collect::<Vec<_>>();
+            Err(Error::AmbiguousObjectName { name, candidates })
         }
     }
-    let message = kvml["message"][0].to_string();
-    ret += format!("\n{}", message).as_str();
-    Ok(ret.as_bytes().to_vec())
 }
 
-pub fn object_read(gitdir: &PathBuf, sha: &str) -> Result<GitObject> {
-    // https://docs.rs/flate2/latest/flate2/read/struct.ZlibDecoder.html
-    let path = gitdir.join("objects").join(&sha[..2]).join(&sha[2..]);
-    anyhow::ensure!(path.is_file(), "{} is not a file", path.display());
-
-    dbg!(&path);
-    let f = File::open(path)?;
-    let mut bin = Vec::new();
-    ZlibDecoder::new(f).read_to_end(&mut bin)?;
-
-    // git fetch --refetch --no-auto-gc
-    let space_at = bin.iter().position(|&b| b == b' ').unwrap();
-    let header = String::from_utf8(bin[..space_at].to_vec())?;
-    let null_at = bin.iter().position(|&b| b == 0).unwrap();
-    let size: usize = String::from_utf8(bin[space_at + 1..null_at].to_vec())?.parse()?;
-    let content = bin[null_at + 1..].to_vec();
-
-    match header.as_str() {
-        "blob" => {
-            anyhow::ensure!(size == content.len(), "Size mismatch");
-            Ok(GitObject::Blob { content })
-        }
-        "commit" => {
-            let mut dct = IndexMap::new();
-            parse_commit(&content, 0, &mut dct)?;
-            Ok(GitObject::Commit {
-                tree: dct["tree"][0].clone(),
-                parent: dct.get("parent").unwrap_or(&vec![]).clone(),
-                author: dct["author"][0].clone(),
-                committer: dct["committer"][0].clone(),
-                // gpgsig: dct[&Some("gpgsig".to_string())][0].clone(),
-                message: dct["message"][0].clone(),
-            })
-        }
-        "tree" => tree_parse(&content),
-        _ => anyhow::bail!("object-read() not support {}", header),
+// tag を辿って kind のオブジェクトまで剥がす (commit は tree まで剥がせる)
+pub fn object_peel(
+    odb: &dyn ObjectDatabase,
+    sha: &ObjectId,
+    kind: GitObjectKind,
+) -> Result<(ObjectId, GitObject)> {
+    let mut sha = *sha;
+    loop {
+        let obj = odb.read(&sha)?;
+        sha = match (obj, &kind) {
+            (obj @ GitObject::Blob { .. }, GitObjectKind::Blob)
+            | (obj @ GitObject::Commit { .. }, GitObjectKind::Commit)
+            | (obj @ GitObject::Tag { .. }, GitObjectKind::Tag)
+            | (obj @ GitObject::Tree(_), GitObjectKind::Tree) => return Ok((sha, obj)),
+            (GitObject::Tag { object, .. }, _) => object,
+            (GitObject::Commit { tree, .. }, GitObjectKind::Tree) => tree,
+            _ => {
+                return Err(Error::UnexpectedObjectKind {
+                    sha,
+                    expected: kind.as_str().to_string(),
+                })
+            }
+        };
+    }
+}
+
+// tree の中身を読む (tag や commit は剥がさない)
+pub fn tree_read(odb: &dyn ObjectDatabase, sha: &ObjectId) -> Result<Vec<TreeOject>> {
+    match odb.read(sha)? {
+        GitObject::Tree(entries) => Ok(entries),
+        _ => Err(Error::UnexpectedObjectKind {
+            sha: *sha,
+            expected: GitObjectKind::Tree.as_str().to_string(),
+        }),
     }
 }
 
 pub fn tree_parse(data: &[u8]) -> Result<GitObject> {
+    let truncated = |_| Error::corrupt("Truncated tree entry");
     let mut objects = Vec::new();
     let mut cursor = Cursor::new(data);
     while cursor.position() < data.len() as u64 {
@@ -332,18 +455,27 @@ pub fn tree_parse(data: &[u8]) -> Result<GitObject> {
             .take_while(|&&b| b != 0x20)
             .count();
         let mut buf: Vec<u8> = vec![0; size];
-        cursor.read_exact(&mut buf)?;
-        let (file_type, permission) = if size == 5 {
-            let file_type = FileType::try_from([b'0', buf[0]].as_slice())?;
-            let permission = String::from_utf8(buf[2..5].to_vec())?;
-            (file_type, permission)
-        } else {
-            let file_type = FileType::try_from(&buf[0..2])?;
-            let permission = String::from_utf8(buf[2..6].to_vec())?;
-            (file_type, permission)
+        cursor.read_exact(&mut buf).map_err(truncated)?;
+        let (file_type, permission) = match size {
+            5 => {
+                let file_type = FileType::try_from([b'0', buf[0]].as_slice())?;
+                let permission = buf[2..5].to_str_lossy().to_string();
+                (file_type, permission)
+            }
+            6 => {
+                let file_type = FileType::try_from(&buf[0..2])?;
+                let permission = buf[2..6].to_str_lossy().to_string();
+                (file_type, permission)
+            }
+            _ => {
+                return Err(Error::corrupt(format!(
+                    "Invalid tree entry mode {}",
+                    buf.as_bstr()
+                )))
+            }
         };
 
-        cursor.seek(SeekFrom::Current(1))?;
+        cursor.seek(SeekFrom::Current(1)).map_err(truncated)?;
         let size = cursor
             .get_ref()
             .iter()
@@ -351,16 +483,13 @@ pub fn tree_parse(data: &[u8]) -> Result<GitObject> {
             .take_while(|&&b| b != 0x00)
             .count();
         let mut buf = vec![0; size];
-        cursor.read_exact(&mut buf)?;
-        let path = PathBuf::from(String::from_utf8(buf)?);
+        cursor.read_exact(&mut buf).map_err(truncated)?;
+        let path = BString::from(buf);
 
-        cursor.seek(SeekFrom::Current(1))?;
+        cursor.seek(SeekFrom::Current(1)).map_err(truncated)?;
         let mut buf = vec![0; 20];
-        cursor.read_exact(&mut buf)?;
-        let sha = buf
-            .iter()
-            .map(|&c| format!("{:02x}", c))
-            .collect::<String>();
+        cursor.read_exact(&mut buf).map_err(truncated)?;
+        let sha = ObjectId::from_bytes(&buf)?;
         let tree = TreeOject {
             file_type,
             permission,
@@ -372,31 +501,25 @@ pub fn tree_parse(data: &[u8]) -> Result<GitObject> {
     Ok(GitObject::Tree(objects))
 }
 
-fn tree_serialize(obj: &GitObject) -> Result<Vec<u8>> {
-    let GitObject::Tree(mut objects) = obj.clone() else {
-        anyhow::bail!("Invalid object");
-    };
-    objects.sort_by_key(|o| {
-        if o.file_type == FileType::RegularFile {
-            o.path.display().to_string()
-        } else {
-            o.path.display().to_string() + "/"
-        }
-    });
+fn tree_serialize(objects: &[TreeOject]) -> Vec<u8> {
+    let mut objects = objects.to_vec();
+    objects.sort_by_key(|o| o.sort_key());
     let mut ret = vec![];
     for o in objects {
-        ret.extend(o.file_type.as_str().as_bytes());
-        ret.extend(o.permission.as_bytes());
+        ret.extend(o.mode().as_bytes());
         ret.push(b' ');
-        ret.extend(o.path.display().to_string().as_bytes());
+        ret.extend(o.path.as_slice());
         ret.push(0x00);
+        // tree には hex ではなく 20 byte の生の SHA-1 を書く
         ret.extend(o.sha.as_bytes());
     }
-    Ok(ret)
+    ret
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{deserialize_object, GitObject, GitObjectKind};
+    use crate::error::Error;
     use sha1::digest::Digest;
 
     #[test]
@@ -406,4 +529,133 @@ mod tests {
         let hash = hex::encode(hash);
         assert_eq!(hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed")
     }
+
+    #[test]
+    fn deserialize_commit() {
+        let data = b"tree 29ff16c9c14e2652b22f8b78bb08a5a07930c147\n\
+parent 206941306e8a8af65b66eaaaea388a7ae24d49a0\n\
+author Thibault Polge <thibault@thb.lt> 1527025023 +0200\n\
+committer Thibault Polge <thibault@thb.lt> 1527025044 +0200\n\
+\n\
+Create first draft";
+        let GitObject::Commit {
+            tree,
+            parent,
+            message,
+            ..
+        } = deserialize_object(GitObjectKind::Commit, data).unwrap()
+        else {
+            panic!("commit expected");
+        };
+        assert_eq!(tree.to_string(), "29ff16c9c14e2652b22f8b78bb08a5a07930c147");
+        assert_eq!(
+            parent[0].to_string(),
+            "206941306e8a8af65b66eaaaea388a7ae24d49a0"
+        );
+        assert_eq!(message, "Create first draft");
+    }
+
+    #[test]
+    fn deserialize_malformed() {
+        let corrupt = |kind, data| {
+            matches!(
+                deserialize_object(kind, data),
+                Err(Error::CorruptObject { .. })
+            )
+        };
+        for data in [&b"tree"[..], b"tree abc", b"tree abc\nx", b"", b"\n"] {
+            assert!(corrupt(GitObjectKind::Commit, data));
+            assert!(corrupt(GitObjectKind::Tag, data));
+        }
+        assert!(corrupt(GitObjectKind::Tree, b"100644 a"));
+        assert!(corrupt(GitObjectKind::Tree, b"1 a\0abc"));
+    }
+
+    #[test]
+    fn tree_round_trip() {
+        // git mktree で作った tree
+        let mut data = Vec::new();
+        for (mode, name, sha) in [
+            (
+                "120000",
+                "a-link",
+                "587be6b4c3f93f93c489c0111bba5596147a26cb",
+            ),
+            (
+                "100644",
+                "a.txt",
+                "65ef226608e282dd5451753dfa9dbb21f230a731",
+            ),
+            ("40000", "a", "2b4c1d0c6f3c005f72eb2ecd2eb2a25edecf9a50"),
+            ("100755", "b", "587be6b4c3f93f93c489c0111bba5596147a26cb"),
+        ] {
+            data.extend(format!("{} {}\0", mode, name).as_bytes());
+            data.extend(hex::decode(sha).unwrap());
+        }
+        let tree = deserialize_object(GitObjectKind::Tree, &data).unwrap();
+        assert_eq!(super::serialize_object(&tree), data);
+        assert_eq!(
+            tree.hash().to_string(),
+            "a355e329edd6df18cfa075a78ee0d2b236a2310e"
+        );
+    }
+
+    #[test]
+    fn commit_keeps_extra_headers() {
+        let data = b"tree afedc9a624416883880e4ead68f649c7356d0390\n\
+author A <a@b> 1792300577 +0000\n\
+committer A <a@b> 1792300577 +0000\n\
+encoding ISO-8859-1\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n iQEzBAABCAAdFiEE\n -----END PGP SIGNATURE-----\n\
+x-custom hello\n\
+\n\
+signed\n";
+        let commit = deserialize_object(GitObjectKind::Commit, data).unwrap();
+        let GitObject::Commit { extra_headers, .. } = &commit else {
+            panic!("commit expected");
+        };
+        let keys = extra_headers
+            .iter()
+            .map(|(k, _)| k.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["encoding", "gpgsig", "x-custom"]);
+        assert_eq!(super::serialize_object(&commit), data);
+    }
+
+    #[test]
+    fn non_utf8_round_trip() {
+        let commit = b"tree afedc9a624416883880e4ead68f649c7356d0390\n\
+author Ren\xe9 <r@e> 1792300577 +0000\n\
+committer Ren\xe9 <r@e> 1792300577 +0000\n\
+encoding ISO-8859-1\n\
+\n\
+caf\xe9\n";
+        let obj = deserialize_object(GitObjectKind::Commit, commit).unwrap();
+        assert_eq!(super::serialize_object(&obj), commit);
+
+        let mut tree = b"100644 caf\xe9.txt\0".to_vec();
+        tree.extend(hex::decode("c1b0730e0133447badcfd47fd144e254807b06e1").unwrap());
+        let obj = deserialize_object(GitObjectKind::Tree, &tree).unwrap();
+        assert_eq!(super::serialize_object(&obj), tree);
+    }
+
+    #[test]
+    fn tag_without_tagger() {
+        // linux の v2.6.11 のような tagger のない tag
+        let data = b"object c39ae07f393806ccf406ef966e9a15afc43cc36a\n\
+type tree\n\
+tag v2.6.11-tree\n\
+\n\
+This is the 2.6.11 tree object.\n";
+        let tag = deserialize_object(GitObjectKind::Tag, data).unwrap();
+        let GitObject::Tag {
+            tagger, tag: name, ..
+        } = &tag
+        else {
+            panic!("tag expected");
+        };
+        assert!(tagger.is_none());
+        assert_eq!(name, "v2.6.11-tree");
+        assert_eq!(super::serialize_object(&tag), data);
+    }
 }
@@ -1,5 +1,16 @@
-use crate::git_config::GitConfig;
+use crate::{
+    commit_graph::CommitGraph,
+    error::Error,
+    git_config::GitConfig,
+    object_cache::{CacheStats, DEFAULT_CACHE_BYTES},
+    object_database::{
+        alternate_dirs, CachedObjectDatabase, CombinedObjectDatabase, LooseObjectDatabase,
+        ObjectDatabase, PackedObjectDatabase,
+    },
+};
 use anyhow::Result;
+use std::cell::OnceCell;
+use std::env;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -8,8 +19,9 @@ fn touch_file(file_path: &PathBuf, content: &[u8]) -> Result<()> {
     let mut f = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(file_path)?;
-    f.write(content)?;
+    f.write_all(content)?;
     Ok(())
 }
 
@@ -61,12 +73,18 @@ pub fn repo_find(path: &Path) -> Result<GitRepository> {
         }
         current = path.parent();
     }
-    anyhow::bail!("git directory not found");
+    Err(Error::NotARepository(path.to_path_buf()).into())
 }
 
 pub struct GitRepository {
     pub worktree: PathBuf,
     pub gitdir: PathBuf,
+    // 書き込み先の objects ディレクトリ (GIT_OBJECT_DIRECTORY で変えられる)
+    pub objects_dir: PathBuf,
+    // 読み込みにも使う別の objects ディレクトリ
+    pub alternates: Vec<PathBuf>,
+    odb: CachedObjectDatabase,
+    commit_graph: OnceCell<Option<CommitGraph>>,
 }
 
 impl GitRepository {
@@ -74,16 +92,39 @@ impl GitRepository {
         let force = force.unwrap_or(false);
         let gitdir = path.join(".git");
 
-        anyhow::ensure!(force || gitdir.is_dir(), "not a git repository: {:?}", path);
+        if !(force || gitdir.is_dir()) {
+            return Err(Error::NotARepository(path).into());
+        }
 
+        let objects_dir = match env::var_os("GIT_OBJECT_DIRECTORY") {
+            Some(dir) => std::path::absolute(dir)?,
+            None => gitdir.join("objects"),
+        };
+        let extra = env::var_os("GIT_ALTERNATE_OBJECT_DIRECTORIES")
+            .map(|dirs| env::split_paths(&dirs).collect::<Vec<_>>())
+            .unwrap_or_default();
+        // 書き込みは先頭の loose object へ行く
+        let alternates = alternate_dirs(&objects_dir, &extra)?;
+        let mut stores: Vec<Box<dyn ObjectDatabase>> = Vec::new();
+        for dir in [&objects_dir].into_iter().chain(&alternates) {
+            stores.push(Box::new(LooseObjectDatabase::new(dir.clone())));
+            stores.push(Box::new(PackedObjectDatabase::new(dir.join("pack"))));
+        }
         let repo = Self {
             worktree: path,
             gitdir,
+            objects_dir,
+            alternates,
+            odb: CachedObjectDatabase::new(
+                Box::new(CombinedObjectDatabase::new(stores)),
+                DEFAULT_CACHE_BYTES,
+            ),
+            commit_graph: OnceCell::new(),
         };
 
         let path = &repo.gitdir.join("config");
         if !force {
-            let conf = GitConfig::read(&path)?;
+            let conf = GitConfig::read(path)?;
             anyhow::ensure!(
                 conf.repository_format_version == 0,
                 "Unsupported repositoryformatversion {:?}",
@@ -93,4 +134,24 @@ impl GitRepository {
 
         Ok(repo)
     }
+
+    pub fn odb(&self) -> &dyn ObjectDatabase {
+        &self.odb
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.odb.stats()
+    }
+
+    // 壊れた commit-graph はなかったものとして commit を直接読む
+    pub fn commit_graph(&self) -> Option<&CommitGraph> {
+        self.commit_graph
+            .get_or_init(|| {
+                CommitGraph::open(&self.objects_dir).unwrap_or_else(|e| {
+                    eprintln!("warning: ignoring commit-graph: {}", e);
+                    None
+                })
+            })
+            .as_ref()
+    }
 }
@@ -1,25 +1,44 @@
 use crate::{
-    git_object::{GitObject, GitObjectKind},
+    git_object::{deserialize_object, GitObjectKind},
     git_repository::repo_find,
+    object_stream::object_hash_stream,
 };
 use anyhow::Result;
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+};
 
 pub fn cmd_hash_object(write: bool, kind: GitObjectKind, path: PathBuf) -> Result<()> {
-    let mut f = File::open(&path)?;
-    let mut content = Vec::new();
-    f.read_to_end(&mut content)?;
-    let gitdir = repo_find(&path)?.gitdir;
+    let repo = repo_find(&path)?;
 
-    let obj = match kind {
-        GitObjectKind::Blob => GitObject::Blob { content },
-        _ => todo!(),
+    let sha = match kind {
+        // blob は巨大なこともあるのでメモリに載せずにストリームで処理する
+        GitObjectKind::Blob => {
+            let f = File::open(&path)?;
+            let size = f.metadata()?.len() as usize;
+            let mut f = BufReader::new(f);
+            if write {
+                repo.odb().write_stream(&kind, size, &mut f)?
+            } else {
+                object_hash_stream(&kind, size, f)?
+            }
+        }
+        // tree などは一度 parse して正しい形式か確かめる
+        // 書き直すと並びや mode の書き方が変わることがあるので、SHA-1 は与えられたバイト列から求める
+        _ => {
+            let data = fs::read(&path)?;
+            deserialize_object(kind.clone(), &data)?;
+            if write {
+                repo.odb()
+                    .write_stream(&kind, data.len(), &mut data.as_slice())?
+            } else {
+                object_hash_stream(&kind, data.len(), data.as_slice())?
+            }
+        }
     };
 
-    if write {
-        obj.write(&gitdir)?;
-    }
-
-    println!("{}", obj.hash()?);
+    println!("{}", sha);
     Ok(())
 }
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use bstr::{BString, ByteSlice};
+use regex::bytes::Regex;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// .gitignore の 1 行
+struct Pattern {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+    // "/" を含まないものはどの深さのファイル名にも一致する
+    basename: bool,
+}
+
+impl Pattern {
+    fn parse(line: &[u8]) -> Result<Option<Self>> {
+        // "\ " で終わらない末尾の空白は無視する
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() || line.starts_with(b"#") {
+            return Ok(None);
+        }
+        let negated = line.starts_with(b"!");
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+        let basename = !line.contains(&b'/');
+        let line = line.strip_prefix(b"/").unwrap_or(line);
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let regex = Regex::new(&format!("(?s-u)^{}$", glob_to_regex(line)))?;
+        Ok(Some(Self {
+            regex,
+            negated,
+            dir_only,
+            basename,
+        }))
+    }
+
+    // path は .gitignore のあるディレクトリからのパス
+    fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let target = match (self.basename, path.rfind_byte(b'/')) {
+            (true, Some(i)) => &path[i + 1..],
+            _ => path,
+        };
+        self.regex.is_match(target)
+    }
+}
+
+// "*" と "?" は "/" をまたがず、"**" は "a/**", "**/b", "a/**/b" の形のときだけ何階層にも一致する
+fn glob_to_regex(glob: &[u8]) -> String {
+    let glob = glob.to_str_lossy().chars().collect::<Vec<_>>();
+    let mut regex = String::new();
+    let mut i = 0;
+    while i < glob.len() {
+        let rest = &glob[i..];
+        match rest[0] {
+            '*' if rest.starts_with(&['*', '*', '/']) && i == 0 => {
+                regex.push_str("(?:.*/)?");
+                i += 3;
+            }
+            '/' if rest.starts_with(&['/', '*', '*', '/']) => {
+                regex.push_str("/(?:.*/)?");
+                i += 4;
+            }
+            '/' if rest == ['/', '*', '*'] => {
+                regex.push_str("/.*");
+                i += 3;
+            }
+            '*' => {
+                regex.push_str("[^/]*");
+                while glob.get(i) == Some(&'*') {
+                    i += 1;
+                }
+            }
+            '?' => {
+                regex.push_str("[^/]");
+                i += 1;
+            }
+            '[' => match rest.iter().skip(2).position(|c| *c == ']') {
+                Some(end) => {
+                    let class = &rest[1..end + 2];
+                    let (negated, class) = match class.first() {
+                        Some('!' | '^') => (true, &class[1..]),
+                        _ => (false, class),
+                    };
+                    // (?-u) の中では ASCII 以外の文字を [] に書けないので、その部分だけ Unicode にする
+                    let unicode = class.iter().any(|c| !c.is_ascii());
+                    if unicode {
+                        regex.push_str("(?u:");
+                    }
+                    regex.push_str(if negated { "[^/" } else { "[" });
+                    for c in class {
+                        if matches!(c, '[' | '\\' | '&' | '~') {
+                            regex.push('\\');
+                        }
+                        regex.push(*c);
+                    }
+                    regex.push(']');
+                    if unicode {
+                        regex.push(')');
+                    }
+                    i += end + 3;
+                }
+                None => {
+                    regex.push_str(r"\[");
+                    i += 1;
+                }
+            },
+            '\\' if rest.len() > 1 => {
+                regex.push_str(&regex::escape(&rest[1].to_string()));
+                i += 2;
+            }
+            c => {
+                regex.push_str(&regex::escape(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    regex
+}
+
+fn read_patterns(path: &Path) -> Result<Vec<Pattern>> {
+    match fs::read(path) {
+        Ok(data) => data
+            .lines()
+            .filter_map(|line| Pattern::parse(line).transpose())
+            .collect::<Result<_>>()
+            .with_context(|| format!("Invalid pattern in {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// .git/info/exclude と各ディレクトリの .gitignore
+// 深いディレクトリの .gitignore ほど優先し、同じファイルの中では後の行を優先する
+pub struct IgnoreRules {
+    worktree: PathBuf,
+    exclude: Vec<Pattern>,
+    // ディレクトリごとの .gitignore (必要になったときに読む)
+    dirs: BTreeMap<BString, Vec<Pattern>>,
+}
+
+impl IgnoreRules {
+    pub fn new(worktree: &Path, gitdir: &Path) -> Result<Self> {
+        Ok(Self {
+            worktree: worktree.to_path_buf(),
+            exclude: read_patterns(&gitdir.join("info").join("exclude"))?,
+            dirs: BTreeMap::new(),
+        })
+    }
+
+    // path は worktree からの "/" 区切りのパス
+    // 無視されたディレクトリの中のものは、! で戻そうとしても無視される
+    pub fn is_ignored(&mut self, path: &[u8], is_dir: bool) -> Result<bool> {
+        let mut end = 0;
+        while let Some(i) = path[end..].find_byte(b'/') {
+            if self.matches(&path[..end + i], true)? {
+                return Ok(true);
+            }
+            end += i + 1;
+        }
+        self.matches(path, is_dir)
+    }
+
+    fn matches(&mut self, path: &[u8], is_dir: bool) -> Result<bool> {
+        // 深いディレクトリから見て、最初に一致したもので決まる
+        let mut dirs = vec![BString::from("")];
+        for (i, c) in path.iter().enumerate() {
+            if *c == b'/' {
+                dirs.push(path[..i].into());
+            }
+        }
+        for dir in dirs.iter().rev() {
+            let relative = if dir.is_empty() {
+                path
+            } else {
+                &path[dir.len() + 1..]
+            };
+            let patterns = self.load(dir)?;
+            if let Some(p) = patterns.iter().rev().find(|p| p.matches(relative, is_dir)) {
+                return Ok(!p.negated);
+            }
+        }
+        let found = self.exclude.iter().rev().find(|p| p.matches(path, is_dir));
+        Ok(found.is_some_and(|p| !p.negated))
+    }
+
+    fn load(&mut self, dir: &BString) -> Result<&Vec<Pattern>> {
+        if !self.dirs.contains_key(dir) {
+            let path = self.worktree.join(dir.to_path()?).join(".gitignore");
+            self.dirs.insert(dir.clone(), read_patterns(&path)?);
+        }
+        Ok(&self.dirs[dir])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    #[test]
+    fn patterns() {
+        let matches = |pattern: &str, path: &str, is_dir: bool| {
+            Pattern::parse(pattern.as_bytes())
+                .unwrap()
+                .unwrap()
+                .matches(path.as_bytes(), is_dir)
+        };
+        assert!(matches("*.o", "a/b/c.o", false));
+        assert!(!matches("*.o", "a/b/c.oo", false));
+        assert!(matches("/build", "build", true));
+        assert!(!matches("/build", "a/build", true));
+        assert!(matches("build/", "a/build", true));
+        assert!(!matches("build/", "a/build", false));
+        assert!(matches("doc/*.txt", "doc/a.txt", false));
+        assert!(!matches("doc/*.txt", "doc/x/a.txt", false));
+        assert!(matches("**/logs", "a/b/logs", true));
+        assert!(matches("**/logs", "logs", true));
+        assert!(matches("a/**/b", "a/b", false));
+        assert!(matches("a/**/b", "a/x/y/b", false));
+        assert!(matches("a/**", "a/x/y", false));
+        assert!(matches("file[0-9].?", "file3.c", false));
+        assert!(!matches("file[!0-9]", "file3", false));
+        assert!(matches("\\#hash", "#hash", false));
+        assert!(matches("trailing  ", "trailing", false));
+        assert!(matches("[éa].txt", "é.txt", false));
+        assert!(matches("[éa].txt", "a.txt", false));
+        assert!(!matches("[!é].txt", "é.txt", false));
+        assert!(matches("ü*", "über", false));
+        assert!(Pattern::parse(b"!keep.o").unwrap().unwrap().negated);
+        assert!(Pattern::parse(b"# comment").unwrap().is_none());
+    }
+}
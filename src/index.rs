@@ -0,0 +1,431 @@
+use crate::{
+    error::{Error, Result},
+    object_id::ObjectId,
+};
+use bstr::{BString, ByteSlice};
+use sha1::{Digest, Sha1};
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+const SIGNATURE: &[u8] = b"DIRC";
+const HEADER_LEN: usize = 12;
+// stat の 10 個の u32, object id, flags
+const ENTRY_FIXED_LEN: usize = 40 + ObjectId::LEN + 2;
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_SHIFT: u16 = 12;
+const NAME_MASK: u16 = 0x0fff;
+
+fn be32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn be16(data: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes(data[pos..pos + 2].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexEntry {
+    // (秒, ナノ秒)
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    // 0o100644, 0o100755, 0o120000 (symlink), 0o160000 (gitlink)
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // 32 bit に収まらない大きさは切り捨てて持つ
+    pub size: u32,
+    pub sha: ObjectId,
+    pub assume_valid: bool,
+    // 0 は通常、1..=3 は merge の衝突中の base, ours, theirs
+    pub stage: u8,
+    // v3 の skip-worktree と intent-to-add
+    pub extended_flags: u16,
+    // worktree からの "/" 区切りのパス
+    pub path: BString,
+}
+
+impl IndexEntry {
+    // worktree のファイルの stat から作る (mode は実行可能かとシンボリックリンクかだけを見る)
+    pub fn new(path: BString, sha: ObjectId, meta: &Metadata) -> Self {
+        let mode = if meta.file_type().is_symlink() {
+            0o120000
+        } else if is_executable(meta) {
+            0o100755
+        } else {
+            0o100644
+        };
+        let mut entry = Self {
+            mode,
+            size: meta.len() as u32,
+            sha,
+            path,
+            ..Default::default()
+        };
+        entry.set_stat(meta);
+        entry
+    }
+
+    #[cfg(unix)]
+    fn set_stat(&mut self, meta: &Metadata) {
+        use std::os::unix::fs::MetadataExt;
+        self.ctime = (meta.ctime() as u32, meta.ctime_nsec() as u32);
+        self.mtime = (meta.mtime() as u32, meta.mtime_nsec() as u32);
+        self.dev = meta.dev() as u32;
+        self.ino = meta.ino() as u32;
+        self.uid = meta.uid();
+        self.gid = meta.gid();
+    }
+
+    #[cfg(not(unix))]
+    fn set_stat(&mut self, meta: &Metadata) {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let time = |t: io::Result<SystemTime>| {
+            let t = t
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            (t.as_secs() as u32, t.subsec_nanos())
+        };
+        self.ctime = time(meta.created());
+        self.mtime = time(meta.modified());
+    }
+
+    // 前に stage したときから変わっていなさそうか (中身は読まない)
+    pub fn stat_matches(&self, meta: &Metadata) -> bool {
+        let mut now = Self::new(self.path.clone(), self.sha, meta);
+        now.assume_valid = self.assume_valid;
+        now.stage = self.stage;
+        now.extended_flags = self.extended_flags;
+        now == *self
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.is_file() && meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &Metadata) -> bool {
+    false
+}
+
+// .git/index (v2, v3)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Index {
+    // パス, stage の順に並べておく
+    pub entries: Vec<IndexEntry>,
+}
+
+// 別のリポジトリの commit を指すエントリの mode
+pub const GITLINK_MODE: u32 = 0o160000;
+
+impl Index {
+    pub fn path(gitdir: &Path) -> PathBuf {
+        gitdir.join("index")
+    }
+
+    // まだ index がなければ空のものを返す
+    pub fn read(gitdir: &Path) -> Result<Self> {
+        match fs::read(Self::path(gitdir)) {
+            Ok(data) => Self::parse(&data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN + ObjectId::LEN || &data[..4] != SIGNATURE {
+            return Err(Error::corrupt("Not an index file"));
+        }
+        let (body, checksum) = data.split_at(data.len() - ObjectId::LEN);
+        // index.skipHash のときは 0 で埋められている
+        if checksum != Sha1::digest(body).as_slice() && checksum.iter().any(|b| *b != 0) {
+            return Err(Error::corrupt("Index checksum mismatch"));
+        }
+        let version = be32(data, 4);
+        if !(2..=3).contains(&version) {
+            return Err(Error::corrupt(format!(
+                "Index version {} is not supported",
+                version
+            )));
+        }
+
+        let truncated = || Error::corrupt("Truncated index entry");
+        let count = be32(data, 8) as usize;
+        let mut entries = Vec::with_capacity(count.min(body.len() / ENTRY_FIXED_LEN));
+        let mut pos = HEADER_LEN;
+        for _ in 0..count {
+            let start = pos;
+            if body.len() < pos + ENTRY_FIXED_LEN {
+                return Err(truncated());
+            }
+            let stat = |i: usize| be32(body, pos + i * 4);
+            let mut entry = IndexEntry {
+                ctime: (stat(0), stat(1)),
+                mtime: (stat(2), stat(3)),
+                dev: stat(4),
+                ino: stat(5),
+                mode: stat(6),
+                uid: stat(7),
+                gid: stat(8),
+                size: stat(9),
+                sha: ObjectId::from_bytes(&body[pos + 40..pos + 40 + ObjectId::LEN])?,
+                ..Default::default()
+            };
+            let flags = be16(body, pos + ENTRY_FIXED_LEN - 2);
+            entry.assume_valid = flags & FLAG_ASSUME_VALID != 0;
+            entry.stage = ((flags >> FLAG_STAGE_SHIFT) & 3) as u8;
+            pos += ENTRY_FIXED_LEN;
+            if flags & FLAG_EXTENDED != 0 {
+                if version < 3 || body.len() < pos + 2 {
+                    return Err(Error::corrupt("Invalid index entry flags"));
+                }
+                entry.extended_flags = be16(body, pos);
+                pos += 2;
+            }
+            // 長いパスは flags に入りきらないので NUL までを読む
+            let len = body[pos..].find_byte(0).ok_or_else(truncated)?;
+            entry.path = body[pos..pos + len].into();
+            // エントリは NUL を 1 つ以上付けて 8 バイト単位にそろえる
+            pos = start + (pos - start + len + 8) / 8 * 8;
+            if body.len() < pos {
+                return Err(truncated());
+            }
+            entries.push(entry);
+        }
+
+        // 知らない任意の拡張 (大文字で始まるもの) は読み飛ばす
+        while pos < body.len() {
+            if body.len() < pos + 8 {
+                return Err(Error::corrupt("Truncated index extension"));
+            }
+            let signature = &body[pos..pos + 4];
+            let size = be32(body, pos + 4) as usize;
+            if !signature[0].is_ascii_uppercase() {
+                return Err(Error::corrupt(format!(
+                    "Index extension {} is not supported",
+                    signature.as_bstr()
+                )));
+            }
+            pos += 8 + size;
+        }
+        if pos != body.len() {
+            return Err(Error::corrupt("Truncated index extension"));
+        }
+        Ok(Self { entries })
+    }
+
+    // 拡張は書かない (TREE などは git が作り直す)
+    pub fn serialize(&self) -> Vec<u8> {
+        let extended = self.entries.iter().any(|e| e.extended_flags != 0);
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&(if extended { 3u32 } else { 2 }).to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            let start = data.len();
+            for n in [
+                entry.ctime.0,
+                entry.ctime.1,
+                entry.mtime.0,
+                entry.mtime.1,
+                entry.dev,
+                entry.ino,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                data.extend_from_slice(&n.to_be_bytes());
+            }
+            data.extend_from_slice(entry.sha.as_bytes());
+            let mut flags = (entry.path.len().min(NAME_MASK as usize) as u16)
+                | ((entry.stage as u16 & 3) << FLAG_STAGE_SHIFT);
+            if entry.assume_valid {
+                flags |= FLAG_ASSUME_VALID;
+            }
+            if entry.extended_flags != 0 {
+                flags |= FLAG_EXTENDED;
+            }
+            data.extend_from_slice(&flags.to_be_bytes());
+            if entry.extended_flags != 0 {
+                data.extend_from_slice(&entry.extended_flags.to_be_bytes());
+            }
+            data.extend_from_slice(&entry.path);
+            let len = data.len() - start;
+            data.resize(start + (len + 8) / 8 * 8, 0);
+        }
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(&checksum);
+        data
+    }
+
+    // path のエントリ (stage ごと) の範囲
+    fn range(&self, path: &[u8]) -> Range<usize> {
+        let start = self.entries.partition_point(|e| e.path.as_slice() < path);
+        let len = self.entries[start..]
+            .iter()
+            .take_while(|e| e.path == path)
+            .count();
+        start..start + len
+    }
+
+    pub fn get(&self, path: &[u8], stage: u8) -> Option<&IndexEntry> {
+        self.entries[self.range(path)]
+            .iter()
+            .find(|e| e.stage == stage)
+    }
+
+    // 同じパスと stage のものは置き換える
+    pub fn add(&mut self, entry: IndexEntry) {
+        let key = (entry.path.as_slice(), entry.stage);
+        match self
+            .entries
+            .binary_search_by(|e| (e.path.as_slice(), e.stage).cmp(&key))
+        {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+    }
+
+    // path のすべての stage を取り除く
+    pub fn remove(&mut self, path: &[u8]) -> bool {
+        let range = self.range(path);
+        if range.is_empty() {
+            return false;
+        }
+        self.entries.drain(range);
+        true
+    }
+
+    // dir の下にあるものをすべて取り除く ("dir/" で始まるものは並びの中で連続している)
+    pub fn remove_dir(&mut self, dir: &[u8]) -> bool {
+        let start = self
+            .entries
+            .partition_point(|e| e.path.as_slice() < [dir, b"/"].concat().as_slice());
+        let end = self
+            .entries
+            .partition_point(|e| e.path.as_slice() < [dir, b"0"].concat().as_slice());
+        if start == end {
+            return false;
+        }
+        self.entries.drain(start..end);
+        true
+    }
+
+    pub fn write(&self, gitdir: &Path) -> Result<()> {
+        IndexLock::acquire(gitdir)?.commit(self)
+    }
+}
+
+// index.lock を作っている間は他のプロセスは index を書けない
+// commit せずに drop したら index.lock を消す
+pub struct IndexLock {
+    lock_path: PathBuf,
+    index_path: PathBuf,
+    file: Option<File>,
+}
+
+impl IndexLock {
+    pub fn acquire(gitdir: &Path) -> Result<Self> {
+        let index_path = Index::path(gitdir);
+        let lock_path = index_path.with_extension("lock");
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Unable to create '{}': File exists. Another git process seems to be running",
+                        lock_path.display()
+                    ),
+                ),
+                _ => e,
+            })?;
+        Ok(Self {
+            lock_path,
+            index_path,
+            file: Some(file),
+        })
+    }
+
+    // 途中で失敗したら drop で index.lock を消し、index はそのまま残す
+    pub fn commit(mut self, index: &Index) -> Result<()> {
+        let file = self.file.as_mut().unwrap();
+        file.write_all(&index.serialize())?;
+        file.sync_all()?;
+        fs::rename(&self.lock_path, &self.index_path)?;
+        self.file.take();
+        Ok(())
+    }
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, IndexEntry};
+    use crate::object_id::ObjectId;
+
+    #[test]
+    fn round_trip() {
+        let id = |n: u8| ObjectId::from_bytes(&[n; 20]).unwrap();
+        let mut index = Index::default();
+        for (path, stage) in [("b.txt", 0), ("a/c.txt", 2), ("a/c.txt", 1), ("a", 0)] {
+            index.add(IndexEntry {
+                mtime: (1_700_000_000, 5),
+                mode: 0o100644,
+                size: 3,
+                sha: id(path.len() as u8),
+                stage,
+                path: path.into(),
+                ..Default::default()
+            });
+        }
+        let paths = index
+            .entries
+            .iter()
+            .map(|e| (e.path.to_string(), e.stage))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [("a", 0), ("a/c.txt", 1), ("a/c.txt", 2), ("b.txt", 0)].map(|(p, s)| (p.into(), s))
+        );
+
+        let data = index.serialize();
+        // 62 + パス + NUL を 8 バイト単位に ("a" は 64, "a/c.txt" は 72)
+        assert_eq!(data.len(), 12 + 64 + 72 * 2 + 72 + 20);
+        assert_eq!(&data[4..8], &2u32.to_be_bytes());
+        assert_eq!(Index::parse(&data).unwrap(), index);
+
+        let mut broken = data.clone();
+        broken[20] ^= 1;
+        assert!(Index::parse(&broken).is_err());
+
+        // skip-worktree があれば v3 で書く
+        index.entries[0].extended_flags = 0x4000;
+        let data = index.serialize();
+        assert_eq!(&data[4..8], &3u32.to_be_bytes());
+        assert_eq!(Index::parse(&data).unwrap(), index);
+        assert_eq!(index.get(b"a/c.txt", 2).map(|e| e.stage), Some(2));
+        assert!(index.remove(b"a/c.txt") && index.entries.len() == 2);
+        assert!(!index.remove_dir(b"a"));
+    }
+}
@@ -0,0 +1,28 @@
+use crate::pack::{scan_pack, write_index_file, IndexEntry};
+use anyhow::Result;
+use std::path::PathBuf;
+
+// 受け取った .pack から同じ名前の .idx を作る (リポジトリの外でもよい)
+pub fn cmd_index_pack(pack_path: PathBuf) -> Result<()> {
+    anyhow::ensure!(
+        pack_path.extension().is_some_and(|ext| ext == "pack"),
+        "packfile name {} does not end with .pack",
+        pack_path.display()
+    );
+    let (entries, checksum) = scan_pack(&pack_path)?;
+    let mut index_entries = entries
+        .iter()
+        .map(|e| IndexEntry {
+            sha: e.sha,
+            offset: e.offset,
+            crc32: e.crc32,
+        })
+        .collect::<Vec<_>>();
+    write_index_file(
+        &pack_path.with_extension("idx"),
+        &mut index_entries,
+        &checksum,
+    )?;
+    println!("{}", checksum);
+    Ok(())
+}
@@ -1,45 +1,252 @@
+use crate::object_id::ObjectId;
 use crate::{
-    git_object::{object_read, GitObject},
+    commit_graph::CommitGraph,
+    git_object::{object_peel, object_resolve, tree_read, GitObject, GitObjectKind},
     git_repository::repo_find,
+    object_database::ObjectDatabase,
 };
 use anyhow::Result;
-use std::{collections::HashSet, path::PathBuf};
+use bstr::{BString, ByteSlice};
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    path::PathBuf,
+};
 
-pub fn cmd_log(object_str: String) -> Result<()> {
+pub fn cmd_log(object_str: String, path: Option<PathBuf>) -> Result<()> {
     let current_dir = std::env::current_dir()?;
-    let gitdir = repo_find(&current_dir)?.gitdir;
+    let repo = repo_find(&current_dir)?;
+    let odb = repo.odb();
     println!("digraph wyaglog {{");
     println!("\tnode [shape=rect];");
     // TODO: HEADに対応
-    let sha = object_read(&gitdir, &object_str)?.hash()?;
-    log_graphviz(&gitdir, sha, &mut HashSet::new())?;
+    let (sha, _) = object_peel(
+        odb,
+        &object_resolve(odb, &object_str)?,
+        GitObjectKind::Commit,
+    )?;
+    match path {
+        Some(path) => {
+            let path = path.as_os_str().as_encoded_bytes();
+            let path = BString::from(path.strip_suffix(b"/").unwrap_or(path));
+            let mut log = PathLog {
+                odb,
+                graph: repo.commit_graph(),
+                path,
+                commits: HashMap::new(),
+                rewritten: HashMap::new(),
+            };
+            log.print(sha)?;
+        }
+        None => log_graphviz(odb, repo.commit_graph(), sha)?,
+    }
     println!("}}");
     Ok(())
 }
 
-fn log_graphviz(gitdir: &PathBuf, sha: String, seen: &mut HashSet<String>) -> Result<()> {
-    if seen.contains(&sha) {
-        return Ok(());
+// 子が親より先に出るよう、generation の大きいものから書く
+fn log_graphviz(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    sha: ObjectId,
+) -> Result<()> {
+    let mut seen = HashSet::from([sha]);
+    let mut queue = BinaryHeap::from([(walk_order(odb, graph, &sha)?, sha)]);
+    while let Some((_, sha)) = queue.pop() {
+        let parent = print_node(odb, &sha)?;
+        // commit-graph があれば親はそこから取る
+        let parent = match graph.map(|g| g.commit(&sha)).transpose()?.flatten() {
+            Some(commit) => commit.parents,
+            None => parent,
+        };
+        for p in parent {
+            println!("\tc_{} -> c_{};", sha, p);
+            if seen.insert(p) {
+                queue.push((walk_order(odb, graph, &p)?, p));
+            }
+        }
+    }
+    Ok(())
+}
+
+// commit-graph にない commit は、そこにある commit の祖先にはならないので generation を最大とみなす
+// generation が同じものは新しいものを先にする
+fn walk_order(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    sha: &ObjectId,
+) -> Result<(u32, i64)> {
+    if let Some(commit) = graph.map(|g| g.commit(sha)).transpose()?.flatten() {
+        return Ok((commit.generation, commit.commit_time));
+    }
+    match odb.read(sha)? {
+        GitObject::Commit { committer, .. } => Ok((u32::MAX, committer.timestamp())),
+        _ => Err(anyhow::anyhow!("Not a commit {}", sha)),
     }
-    seen.insert(sha.clone());
+}
+
+// commit を 1 つの node として書き、親を返す
+fn print_node(odb: &dyn ObjectDatabase, sha: &ObjectId) -> Result<Vec<ObjectId>> {
     let GitObject::Commit {
-        parent, message, ..
-    } = object_read(gitdir, &sha)?
+        parent,
+        author,
+        message,
+        ..
+    } = odb.read(sha)?
     else {
         return Err(anyhow::anyhow!("Not a commit {}", sha));
     };
-    let short_hash = &sha[..=7];
+    let short_hash = &sha.to_hex()[..=7];
+    // メッセージは UTF-8 とは限らないので表示するときに変換する
+    let message = message.to_str_lossy();
     let message = message.replace("\\", "\\\\");
     let message = message.replace("\"", "\\\"");
     let message = message.split("\n").collect::<Vec<_>>()[0];
 
+    let date = author
+        .local_time()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S %z").to_string())
+        .unwrap_or_default();
+
     println!(
-        "\t\"c_{}\" [label=\"{}\\n{}\", shape=rect];",
-        sha, short_hash, message
+        "\t\"c_{}\" [label=\"{} {}\\n{}\", shape=rect];",
+        sha, short_hash, date, message
     );
-    for p in parent {
-        println!("\tc_{} -> c_{};", sha, p);
-        log_graphviz(gitdir, p, seen)?;
+    Ok(parent)
+}
+
+// path を変えた commit だけを表示する (git log -- <path> と同じ簡略化)
+// 親と path が同じ commit は飛ばし、merge ではその親だけをたどる
+struct PathLog<'a> {
+    odb: &'a dyn ObjectDatabase,
+    graph: Option<&'a CommitGraph>,
+    path: BString,
+    // 表示するかどうかと、たどる親
+    commits: HashMap<ObjectId, (bool, Vec<ObjectId>)>,
+    // 各 commit から一番近い表示する commit たち (表示するものは自分自身)
+    rewritten: HashMap<ObjectId, Vec<ObjectId>>,
+}
+
+impl PathLog<'_> {
+    fn tree_and_parents(&self, sha: &ObjectId) -> Result<(ObjectId, Vec<ObjectId>)> {
+        if let Some(commit) = self.graph.map(|g| g.commit(sha)).transpose()?.flatten() {
+            return Ok((commit.tree, commit.parents));
+        }
+        match self.odb.read(sha)? {
+            GitObject::Commit { tree, parent, .. } => Ok((tree, parent)),
+            _ => Err(anyhow::anyhow!("Not a commit {}", sha)),
+        }
+    }
+
+    // tree の中で path が指す (mode, sha)
+    fn entry(&self, tree: ObjectId) -> Result<Option<(String, ObjectId)>> {
+        let mut entry = ("40000".to_string(), tree);
+        for name in self.path.split_str("/") {
+            if entry.0 != "40000" {
+                return Ok(None);
+            }
+            let found = tree_read(self.odb, &entry.1)?
+                .into_iter()
+                .find(|e| e.path == name);
+            match found {
+                Some(e) => entry = (e.mode(), e.sha),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    // 最初の親との比較は Bloom filter で「確実に変えていない」とわかれば tree を読まない
+    fn maybe_changed(&self, sha: &ObjectId) -> bool {
+        let Some(graph) = self.graph else {
+            return true;
+        };
+        // filter には上のディレクトリも入っている
+        std::iter::once(self.path.len())
+            .chain(self.path.find_iter("/"))
+            .all(|end| graph.maybe_changed(sha, &self.path[..end]))
+    }
+
+    fn simplify(&mut self, sha: ObjectId) -> Result<(bool, Vec<ObjectId>)> {
+        if let Some(commit) = self.commits.get(&sha) {
+            return Ok(commit.clone());
+        }
+        let (tree, parents) = self.tree_and_parents(&sha)?;
+        let mut result = None;
+        for (i, p) in parents.iter().enumerate() {
+            if i == 0 && !self.maybe_changed(&sha) {
+                result = Some((false, vec![*p]));
+                break;
+            }
+            let (parent_tree, _) = self.tree_and_parents(p)?;
+            if self.entry(tree)? == self.entry(parent_tree)? {
+                result = Some((false, vec![*p]));
+                break;
+            }
+        }
+        let result = match result {
+            Some(result) => result,
+            // 根の commit は path があれば表示する
+            None if parents.is_empty() => (self.entry(tree)?.is_some(), Vec::new()),
+            None => (true, parents),
+        };
+        self.commits.insert(sha, result.clone());
+        Ok(result)
+    }
+
+    // sha から親をたどって最初に見つかる表示する commit たち
+    // 履歴が長くてもスタックを使い切らないよう、親の結果が揃ってから自分の結果を決める
+    fn nearest(&mut self, sha: ObjectId) -> Result<Vec<ObjectId>> {
+        let mut stack = vec![sha];
+        while let Some(&top) = stack.last() {
+            if self.rewritten.contains_key(&top) {
+                stack.pop();
+                continue;
+            }
+            let (shown, parents) = self.simplify(top)?;
+            if shown {
+                self.rewritten.insert(top, vec![top]);
+                stack.pop();
+                continue;
+            }
+            let pending = parents
+                .iter()
+                .filter(|p| !self.rewritten.contains_key(p))
+                .copied()
+                .collect::<Vec<_>>();
+            if !pending.is_empty() {
+                stack.extend(pending);
+                continue;
+            }
+            stack.pop();
+            let mut nearest = Vec::new();
+            for p in &parents {
+                for n in &self.rewritten[p] {
+                    if !nearest.contains(n) {
+                        nearest.push(*n);
+                    }
+                }
+            }
+            self.rewritten.insert(top, nearest);
+        }
+        Ok(self.rewritten[&sha].clone())
+    }
+
+    fn print(&mut self, sha: ObjectId) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut stack = self.nearest(sha)?;
+        while let Some(sha) = stack.pop() {
+            if !seen.insert(sha) {
+                continue;
+            }
+            print_node(self.odb, &sha)?;
+            let (_, parents) = self.simplify(sha)?;
+            for p in parents {
+                for n in self.nearest(p)? {
+                    println!("\tc_{} -> c_{};", sha, n);
+                    stack.push(n);
+                }
+            }
+        }
+        Ok(())
     }
-    Ok(())
 }
@@ -0,0 +1,35 @@
+use crate::{git_repository::repo_find, index::Index};
+use anyhow::Result;
+use bstr::{BString, ByteSlice};
+
+// サブディレクトリで実行したら、その下にあるものだけをそこからのパスで表示する
+pub fn cmd_ls_files(stage: bool) -> Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    let prefix = current_dir.strip_prefix(&repo.worktree)?;
+    let mut prefix = BString::from(
+        <[u8]>::from_path(prefix).ok_or_else(|| anyhow::anyhow!("Invalid path {:?}", prefix))?,
+    );
+    if !prefix.is_empty() {
+        prefix.push(b'/');
+    }
+
+    let index = Index::read(&repo.gitdir)?;
+    for entry in &index.entries {
+        let Some(path) = entry.path.strip_prefix(prefix.as_slice()) else {
+            continue;
+        };
+        if stage {
+            println!(
+                "{:06o} {} {}\t{}",
+                entry.mode,
+                entry.sha,
+                entry.stage,
+                path.as_bstr()
+            );
+        } else {
+            println!("{}", path.as_bstr());
+        }
+    }
+    Ok(())
+}
@@ -1,37 +1,56 @@
 use crate::{
-    git_object::{object_read, GitObject, GitObjectKind},
+    git_object::{object_peel, object_resolve, GitObject, GitObjectKind, TreeOject},
     git_repository::repo_find,
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
 };
 use anyhow::Result;
-use std::path::PathBuf;
+use bstr::BString;
 
 pub fn cmd_ls_tree(tree: String, recursive: bool) -> Result<()> {
     let current_dir = std::env::current_dir()?;
-    let gitdir = repo_find(&current_dir)?.gitdir;
-    ls_tree(&gitdir, tree, recursive, &PathBuf::from(""))?;
+    let repo = repo_find(&current_dir)?;
+    let sha = object_resolve(repo.odb(), &tree)?;
+    tree_walk(repo.odb(), &sha, &BString::from(""), &mut |o, path| {
+        let kind = o.file_type.kind();
+        if recursive && kind == GitObjectKind::Tree {
+            return Ok(true);
+        }
+        println!(
+            "{}{:0>4} {} {}\t{}",
+            o.file_type.as_str(),
+            o.permission,
+            kind.as_str(),
+            o.sha,
+            path,
+        );
+        Ok(false)
+    })?;
 
     Ok(())
 }
 
-fn ls_tree(gitdir: &PathBuf, r#ref: String, recursive: bool, prefix: &PathBuf) -> Result<()> {
-    let sha = r#ref.clone();
-    let obj = object_read(gitdir, &sha)?;
+// tree のエントリを順に visit に渡し、visit が true を返した tree はその中もたどる
+// visit にはエントリと prefix からのパスを渡す
+pub fn tree_walk(
+    odb: &dyn ObjectDatabase,
+    sha: &ObjectId,
+    prefix: &BString,
+    visit: &mut dyn FnMut(&TreeOject, &BString) -> Result<bool>,
+) -> Result<()> {
+    let (_, obj) = object_peel(odb, sha, GitObjectKind::Tree)?;
     let GitObject::Tree(objects) = obj else {
         return Err(anyhow::anyhow!("Expected tree, got {:?}", obj));
     };
     for o in objects {
-        let kind = o.file_type.kind();
-        if !recursive || kind != GitObjectKind::Tree {
-            println!(
-                "{}{:0>4} {} {}\t{}",
-                o.file_type.as_str(),
-                o.permission,
-                kind.as_str(),
-                o.sha,
-                prefix.join(o.path).display(),
-            );
+        // パスは UTF-8 とは限らないので表示するときだけ変換する
+        let path = if prefix.is_empty() {
+            o.path.clone()
         } else {
-            ls_tree(gitdir, o.sha, recursive, &prefix.join(o.path))?;
+            BString::from([prefix.as_slice(), b"/", o.path.as_slice()].concat())
+        };
+        if visit(&o, &path)? && o.file_type.kind() == GitObjectKind::Tree {
+            tree_walk(odb, &o.sha, &path, visit)?;
         }
     }
     Ok(())
@@ -1,32 +1,78 @@
+use add::{cmd_add, AddOptions};
 use anyhow::Result;
 use cat_file::cmd_cat_file;
 use checkout::cmd_checkout;
 use clap::Parser;
+use commit_graph::cmd_commit_graph_write;
+use count_objects::cmd_count_objects;
+use gc::cmd_gc;
 use git_object::GitObjectKind;
 use hash_object::cmd_hash_object;
+use index_pack::cmd_index_pack;
 use init::cmd_init;
 use log::cmd_log;
+use ls_files::cmd_ls_files;
 use ls_tree::cmd_ls_tree;
+use multi_pack_index::{cmd_multi_pack_index_verify, cmd_multi_pack_index_write};
+use pack::DeltaOptions;
+use prune::cmd_prune;
+use repack::{cmd_repack, RepackOptions};
 use show_ref::cmd_show_ref;
 use std::{env, path::PathBuf};
 use tag::{cmd_ls_tag, cmd_tag};
+use verify_pack::cmd_verify_pack;
 
+mod add;
+mod bloom;
 mod cat_file;
 mod checkout;
+mod chunk_format;
+mod commit_graph;
+mod count_objects;
+mod delta;
+mod error;
+mod ewah;
+mod gc;
 mod git_config;
 mod git_object;
 mod git_repository;
 mod hash_object;
+mod ignore;
+mod index;
+mod index_pack;
 mod init;
 mod log;
+mod ls_files;
 mod ls_tree;
+mod multi_pack_index;
+mod object_cache;
+mod object_database;
+mod object_id;
+mod object_stream;
+mod pack;
+mod pack_bitmap;
+mod prune;
+mod reachable;
+mod repack;
 mod show_ref;
+mod signature;
 mod tag;
+mod verify_pack;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, clap::Parser)]
 enum CLI {
     Add {
-        path: PathBuf,
+        // pathspec がなくても worktree 全体の追加、変更、削除を stage する
+        #[arg(short = 'A', long)]
+        all: bool,
+        // index にあるものの変更と削除だけを stage する
+        #[arg(short, long)]
+        update: bool,
+        // 無視されるファイルも追加する
+        #[arg(short, long)]
+        force: bool,
+        paths: Vec<PathBuf>,
     },
     CatFile {
         kind: GitObjectKind,
@@ -38,6 +84,25 @@ enum CLI {
         path: PathBuf,
     },
     Commit,
+    CommitGraph {
+        #[command(subcommand)]
+        command: CommitGraphCommand,
+    },
+    CountObjects {
+        #[arg(short, long)]
+        verbose: bool,
+        // ref からたどれる大きな blob, 深い tree, 広い tree, 親の多い commit を表示する
+        #[arg(long)]
+        sizer: bool,
+        // --sizer でそれぞれ何件表示するか
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    Gc {
+        // これより古い到達できない loose object を消す
+        #[arg(long, default_value = "2.weeks.ago")]
+        prune: String,
+    },
     HashObject {
         // -w オプションとして使えるようにする
         #[arg(short)]
@@ -46,18 +111,55 @@ enum CLI {
         kind: GitObjectKind,
         path: PathBuf,
     },
+    IndexPack {
+        pack: PathBuf,
+    },
     Init {
         path: PathBuf,
     },
     Log {
         object: String,
+        // この path を変えた commit だけを表示する
+        path: Option<PathBuf>,
+    },
+    LsFiles {
+        // mode, object id, stage も表示する
+        #[arg(short, long)]
+        stage: bool,
     },
-    LsFiles,
     LsTree {
         tree: String,
         #[arg(short)]
         recursive: bool,
     },
+    MultiPackIndex {
+        #[command(subcommand)]
+        command: MultiPackIndexCommand,
+    },
+    Prune {
+        // 消さずに一覧を表示する
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        // これより新しいものは残す (指定しなければすべて消す)
+        #[arg(long)]
+        expire: Option<String>,
+    },
+    Repack {
+        // pack 済みのものも含めて 1 つの pack にする
+        #[arg(short)]
+        all: bool,
+        // pack に入れた loose object (-a なら古い pack も) を消す
+        #[arg(short)]
+        delete: bool,
+        // .bitmap も書く (-a のときだけ)
+        #[arg(short = 'b', long = "write-bitmap-index")]
+        write_bitmap: bool,
+        // delta の base を探す範囲と連鎖の長さ
+        #[arg(long, default_value_t = DeltaOptions::default().window)]
+        window: usize,
+        #[arg(long, default_value_t = DeltaOptions::default().depth)]
+        depth: usize,
+    },
     RevParse,
     Rm,
     ShowRef,
@@ -70,11 +172,30 @@ enum CLI {
         annotate: bool,
         object: String,
     },
+    VerifyPack {
+        #[arg(short)]
+        verbose: bool,
+        // .idx か .pack のどちらか
+        pack: PathBuf,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum CommitGraphCommand {
+    // objects/info/commit-graph を作り直す
+    Write,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum MultiPackIndexCommand {
+    // objects/pack/multi-pack-index を今ある pack から作り直す
+    Write,
+    Verify,
 }
 
 fn parse() -> Result<CLI> {
     let args = env::args().collect::<Vec<_>>();
-    if args.len() == 2 && args[1] == "tag".to_string() {
+    if args.len() == 2 && args[1] == "tag" {
         return Ok(CLI::LsTag);
     }
     CLI::try_parse_from(args).map_err(|e| e.into())
@@ -84,16 +205,48 @@ fn main() -> Result<()> {
     // cargo run -- --hoge fuga
     // -- はcargo runの引数とclapの引数を分けるために必要
     match parse()? {
-        CLI::Add { .. } => todo!(),
+        CLI::Add {
+            all,
+            update,
+            force,
+            paths,
+        } => cmd_add(paths, AddOptions { all, update, force })?,
         CLI::CatFile { kind, object } => cmd_cat_file(kind, object)?,
         CLI::CheckIgnore => todo!(),
         CLI::Checkout { commit, path } => cmd_checkout(commit, path)?,
         CLI::Commit => todo!(),
+        CLI::CommitGraph { command } => match command {
+            CommitGraphCommand::Write => cmd_commit_graph_write()?,
+        },
+        CLI::CountObjects {
+            verbose,
+            sizer,
+            top,
+        } => cmd_count_objects(verbose, sizer, top)?,
+        CLI::Gc { prune } => cmd_gc(prune)?,
         CLI::HashObject { write, kind, path } => cmd_hash_object(write, kind, path)?,
+        CLI::IndexPack { pack } => cmd_index_pack(pack)?,
         CLI::Init { path } => cmd_init(path)?,
-        CLI::Log { object } => cmd_log(object)?,
-        CLI::LsFiles => todo!(),
+        CLI::Log { object, path } => cmd_log(object, path)?,
+        CLI::LsFiles { stage } => cmd_ls_files(stage)?,
         CLI::LsTree { tree, recursive } => cmd_ls_tree(tree, recursive)?,
+        CLI::MultiPackIndex { command } => match command {
+            MultiPackIndexCommand::Write => cmd_multi_pack_index_write()?,
+            MultiPackIndexCommand::Verify => cmd_multi_pack_index_verify()?,
+        },
+        CLI::Prune { dry_run, expire } => cmd_prune(expire, dry_run)?,
+        CLI::Repack {
+            all,
+            delete,
+            write_bitmap,
+            window,
+            depth,
+        } => cmd_repack(RepackOptions {
+            all,
+            delete,
+            write_bitmap,
+            delta: DeltaOptions { window, depth },
+        })?,
         CLI::RevParse => todo!(),
         CLI::Rm => todo!(),
         CLI::ShowRef => cmd_show_ref()?,
@@ -104,6 +257,7 @@ fn main() -> Result<()> {
             annotate,
             object,
         } => cmd_tag(name, annotate, object)?,
+        CLI::VerifyPack { verbose, pack } => cmd_verify_pack(verbose, pack)?,
     }
 
     // indexmap が入れた順番に取り出せる
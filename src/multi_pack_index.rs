@@ -0,0 +1,383 @@
+use crate::{
+    chunk_format::{fanout_chunk, fanout_lookup, read_chunks, write_chunks, ChunkId},
+    error::{Error, Result},
+    git_repository::repo_find,
+    object_id::ObjectId,
+    object_stream::HashWriter,
+    pack::{pack_list, tmp_path, Pack, PackIndex},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+const SIGNATURE: &[u8] = b"MIDX";
+// signature, version, hash version, chunk の数, base の数, pack の数
+const HEADER_LEN: usize = 12;
+const PNAM: ChunkId = *b"PNAM";
+const OIDF: ChunkId = *b"OIDF";
+const OIDL: ChunkId = *b"OIDL";
+const OOFF: ChunkId = *b"OOFF";
+const LOFF: ChunkId = *b"LOFF";
+// OOFF の 1 行: pack の番号と offset
+const OOFF_LEN: usize = 8;
+// LOFF があるとき、この bit が立った offset は LOFF の位置を表す
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+fn be32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+// objects/pack/multi-pack-index: 複数の pack の .idx を 1 つにまとめたもの
+pub struct MultiPackIndex {
+    // .idx のファイル名 (並べ替え済み)
+    pub pack_names: Vec<String>,
+    fanout: Vec<u8>,
+    oids: Vec<u8>,
+    offsets: Vec<u8>,
+    large_offsets: Option<Vec<u8>>,
+}
+
+impl MultiPackIndex {
+    pub fn path(pack_dir: &Path) -> PathBuf {
+        pack_dir.join("multi-pack-index")
+    }
+
+    // ファイルがなければ None
+    pub fn open(pack_dir: &Path) -> Result<Option<Self>> {
+        match fs::read(Self::path(pack_dir)) {
+            Ok(data) => Ok(Some(Self::parse(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN || &data[..4] != SIGNATURE {
+            return Err(Error::corrupt("Not a multi-pack-index file"));
+        }
+        if data[4] != 1 || data[5] != 1 {
+            return Err(Error::corrupt(format!(
+                "Unsupported multi-pack-index version {} (hash version {})",
+                data[4], data[5]
+            )));
+        }
+        if data[7] != 0 {
+            return Err(Error::corrupt(
+                "Incremental multi-pack-index is not supported",
+            ));
+        }
+        let pack_count = be32(data, 8) as usize;
+        let chunks = read_chunks(data, HEADER_LEN, data[6] as usize)?;
+        let chunk = |id: ChunkId| {
+            chunks.get(&id).copied().ok_or_else(|| {
+                Error::corrupt(format!(
+                    "multi-pack-index has no {} chunk",
+                    String::from_utf8_lossy(&id)
+                ))
+            })
+        };
+
+        // NUL 区切りの名前の後ろに 4 byte 境界までの詰め物がある
+        let pack_names = chunk(PNAM)?
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect::<Vec<_>>();
+        if pack_names.len() != pack_count {
+            return Err(Error::corrupt(format!(
+                "multi-pack-index lists {} packs but its header says {}",
+                pack_names.len(),
+                pack_count
+            )));
+        }
+
+        // 累積数なので減ることはなく、最後の値が object の数になる
+        let fanout = chunk(OIDF)?;
+        if fanout.len() != 256 * 4
+            || (1..256).any(|i| be32(fanout, (i - 1) * 4) > be32(fanout, i * 4))
+        {
+            return Err(Error::corrupt("Invalid multi-pack-index fanout"));
+        }
+        let count = be32(fanout, 255 * 4) as usize;
+        let oids = chunk(OIDL)?;
+        let offsets = chunk(OOFF)?;
+        if oids.len() != count * ObjectId::LEN || offsets.len() != count * OOFF_LEN {
+            return Err(Error::corrupt("multi-pack-index chunk sizes do not match"));
+        }
+        Ok(Self {
+            pack_names,
+            fanout: fanout.to_vec(),
+            oids: oids.to_vec(),
+            offsets: offsets.to_vec(),
+            large_offsets: chunks.get(&LOFF).map(|c| c.to_vec()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.oids.len() / ObjectId::LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.oids.is_empty()
+    }
+
+    pub fn id(&self, i: usize) -> ObjectId {
+        ObjectId::from_bytes(&self.oids[i * ObjectId::LEN..(i + 1) * ObjectId::LEN]).unwrap()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        (0..self.len()).map(|i| self.id(i))
+    }
+
+    pub fn lookup(&self, sha: &ObjectId) -> Option<usize> {
+        fanout_lookup(&self.fanout, &self.oids, sha)
+    }
+
+    // i 番目の object が入っている pack の番号と、その中での offset
+    pub fn entry(&self, i: usize) -> Result<(usize, u64)> {
+        let pack = be32(&self.offsets, i * OOFF_LEN) as usize;
+        if pack >= self.pack_names.len() {
+            return Err(Error::corrupt(format!(
+                "multi-pack-index pack {} is out of range",
+                pack
+            )));
+        }
+        let offset = be32(&self.offsets, i * OOFF_LEN + 4);
+        let offset = match &self.large_offsets {
+            Some(large) if offset & LARGE_OFFSET != 0 => {
+                let pos = (offset & !LARGE_OFFSET) as usize * 8;
+                let bytes = large.get(pos..pos + 8).ok_or(Error::corrupt(
+                    "multi-pack-index large offset is out of range",
+                ))?;
+                u64::from_be_bytes(bytes.try_into().unwrap())
+            }
+            _ => offset as u64,
+        };
+        Ok((pack, offset))
+    }
+
+    pub fn find_prefix(&self, prefix: &str) -> Vec<ObjectId> {
+        let Some(first) = prefix.get(..2).and_then(|s| u8::from_str_radix(s, 16).ok()) else {
+            return Vec::new();
+        };
+        let count = |i: usize| be32(&self.fanout, i * 4) as usize;
+        let lo = if first == 0 {
+            0
+        } else {
+            count(first as usize - 1)
+        };
+        (lo..count(first as usize))
+            .map(|i| self.id(i))
+            .filter(|id| id.to_hex().starts_with(prefix))
+            .collect()
+    }
+}
+
+fn pack_name(idx_path: &Path) -> String {
+    idx_path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+// 複数の pack にある object は新しい pack のものを使う (同じなら名前順で先の pack)
+pub fn serialize_multi_pack_index(packs: &[(String, &PackIndex, i64)]) -> Result<Vec<u8>> {
+    let mut entries: BTreeMap<ObjectId, (usize, u64, i64)> = BTreeMap::new();
+    for (pack_id, (_, index, mtime)) in packs.iter().enumerate() {
+        for i in 0..index.len() {
+            let entry = (pack_id, index.offset(i), *mtime);
+            entries
+                .entry(index.id(i))
+                .and_modify(|e| {
+                    if mtime > &e.2 {
+                        *e = entry;
+                    }
+                })
+                .or_insert(entry);
+        }
+    }
+
+    let mut pnam = Vec::new();
+    for (name, _, _) in packs {
+        pnam.extend_from_slice(name.as_bytes());
+        pnam.push(0);
+    }
+    pnam.resize(pnam.len().div_ceil(4) * 4, 0);
+
+    // 4 GiB を超える offset があるときだけ、2 GiB 以上の offset を LOFF に置く
+    let needs_large = entries.values().any(|e| e.1 > u32::MAX as u64);
+    let mut ooff = Vec::with_capacity(entries.len() * OOFF_LEN);
+    let mut loff = Vec::new();
+    for (pack_id, offset, _) in entries.values() {
+        ooff.extend_from_slice(&(*pack_id as u32).to_be_bytes());
+        let offset = if needs_large && *offset >> 31 != 0 {
+            let n = (loff.len() / 8) as u32 | LARGE_OFFSET;
+            loff.extend_from_slice(&offset.to_be_bytes());
+            n
+        } else {
+            *offset as u32
+        };
+        ooff.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    let ids = entries.keys().copied().collect::<Vec<_>>();
+    let mut chunks = vec![
+        (PNAM, pnam),
+        (OIDF, fanout_chunk(&ids)),
+        (OIDL, ids.iter().flat_map(|id| *id.as_bytes()).collect()),
+        (OOFF, ooff),
+    ];
+    if needs_large {
+        chunks.push((LOFF, loff));
+    }
+
+    let mut w = HashWriter::new(Vec::new());
+    w.write_all(SIGNATURE)?;
+    w.write_all(&[1, 1, chunks.len() as u8, 0])?;
+    w.write_all(&(packs.len() as u32).to_be_bytes())?;
+    write_chunks(&mut w, HEADER_LEN, &chunks)?;
+    let (mut data, checksum) = w.finish();
+    data.extend_from_slice(checksum.as_bytes());
+    Ok(data)
+}
+
+// pack の名前と .pack の更新日時
+fn open_packs(pack_dir: &Path) -> Result<Vec<(String, Pack, i64)>> {
+    pack_list(pack_dir)?
+        .iter()
+        .map(|idx_path| {
+            let pack = Pack::open(idx_path)?;
+            let mtime = fs::metadata(&pack.pack_path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            Ok((pack_name(idx_path), pack, mtime))
+        })
+        .collect()
+}
+
+pub fn cmd_multi_pack_index_write() -> anyhow::Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    let pack_dir = repo.objects_dir.join("pack");
+    let packs = open_packs(&pack_dir)?;
+    anyhow::ensure!(!packs.is_empty(), "no pack files to index");
+    let data = serialize_multi_pack_index(
+        &packs
+            .iter()
+            .map(|(name, pack, mtime)| (name.clone(), &pack.index, *mtime))
+            .collect::<Vec<_>>(),
+    )?;
+
+    let tmp = tmp_path(&pack_dir, "tmp_midx");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, MultiPackIndex::path(&pack_dir))?;
+    println!("Wrote multi-pack-index with {} packs", packs.len());
+    Ok(())
+}
+
+// checksum と、中身が各 pack の .idx と一致しているかを確かめる
+pub fn cmd_multi_pack_index_verify() -> anyhow::Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    let pack_dir = repo.objects_dir.join("pack");
+    let path = MultiPackIndex::path(&pack_dir);
+    let data = fs::read(&path)?;
+    anyhow::ensure!(
+        PackIndex::checksum_ok(&data),
+        "{} has a wrong checksum",
+        path.display()
+    );
+    let midx = MultiPackIndex::parse(&data)?;
+    anyhow::ensure!(
+        midx.pack_names.windows(2).all(|w| w[0] < w[1]),
+        "pack names are out of order"
+    );
+    let packs = midx
+        .pack_names
+        .iter()
+        .map(|name| Pack::open(&pack_dir.join(name)))
+        .collect::<Result<Vec<_>>>()?;
+
+    for i in 0..midx.len() {
+        let sha = midx.id(i);
+        anyhow::ensure!(
+            i == 0 || midx.id(i - 1) < sha,
+            "object ids are out of order at {}",
+            sha
+        );
+        anyhow::ensure!(midx.lookup(&sha) == Some(i), "fanout is wrong for {}", sha);
+        let (pack_id, offset) = midx.entry(i)?;
+        let index = &packs[pack_id].index;
+        anyhow::ensure!(
+            index.lookup(&sha).map(|j| index.offset(j)) == Some(offset),
+            "{} has a wrong offset for {}",
+            sha,
+            midx.pack_names[pack_id]
+        );
+    }
+    // pack にある object はすべて入っているか
+    for (name, pack) in midx.pack_names.iter().zip(&packs) {
+        if let Some(sha) = pack.index.ids().find(|sha| midx.lookup(sha).is_none()) {
+            anyhow::bail!("{} from {} is missing", sha, name);
+        }
+    }
+    println!("{}: ok", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serialize_multi_pack_index, MultiPackIndex};
+    use crate::{
+        chunk_format::fanout_chunk,
+        object_id::ObjectId,
+        pack::{write_index, IndexEntry, PackIndex},
+    };
+
+    #[test]
+    fn round_trip() {
+        let id = |n: u8| ObjectId::from_bytes(&[n; 20]).unwrap();
+        let index = |entries: &[(u8, u64)]| {
+            let mut entries = entries
+                .iter()
+                .map(|&(n, offset)| IndexEntry {
+                    sha: id(n),
+                    offset,
+                    crc32: 0,
+                })
+                .collect::<Vec<_>>();
+            let (data, _) = write_index(Vec::new(), &mut entries, &id(0)).unwrap();
+            PackIndex::parse(&data).unwrap()
+        };
+        // 2 は両方の pack にあり、新しい b のものを使う
+        let a = index(&[(1, 12), (2, 40)]);
+        let b = index(&[(2, 12), (3, 1 << 33), (4, 0x9000_0000)]);
+        let data = serialize_multi_pack_index(&[
+            ("pack-a.idx".to_string(), &a, 100),
+            ("pack-b.idx".to_string(), &b, 200),
+        ])
+        .unwrap();
+        let midx = MultiPackIndex::parse(&data).unwrap();
+        assert_eq!(midx.pack_names, ["pack-a.idx", "pack-b.idx"]);
+        assert_eq!(midx.len(), 4);
+        let entry = |n: u8| midx.entry(midx.lookup(&id(n)).unwrap()).unwrap();
+        assert_eq!(entry(1), (0, 12));
+        assert_eq!(entry(2), (1, 12));
+        assert_eq!(entry(3), (1, 1 << 33));
+        assert_eq!(entry(4), (1, 0x9000_0000));
+        assert!(midx.lookup(&id(5)).is_none());
+        assert_eq!(midx.find_prefix("0303"), vec![id(3)]);
+
+        // 途中で減っている fanout は読まない
+        let fanout = fanout_chunk(&midx.ids().collect::<Vec<_>>());
+        let pos = data
+            .windows(fanout.len())
+            .position(|w| w == fanout)
+            .unwrap();
+        let mut broken = data.clone();
+        broken[pos + 4..pos + 8].copy_from_slice(&4u32.to_be_bytes());
+        assert!(MultiPackIndex::parse(&broken).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{git_object::GitObjectKind, object_id::ObjectId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+};
+
+// 合計がこの大きさを超えたら古く使われたものから捨てる
+pub const DEFAULT_CACHE_BYTES: usize = 32 << 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // キャッシュしない blob を読んだ回数 (hits にも misses にも数えない)
+    pub bypassed: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} bypassed, {} objects ({} bytes)",
+            self.hits, self.misses, self.bypassed, self.entries, self.bytes
+        )
+    }
+}
+
+struct Entry {
+    kind: GitObjectKind,
+    data: Rc<[u8]>,
+    // order のキー
+    used: u64,
+}
+
+// 中身の大きさで上限を決める LRU
+pub struct ObjectCache {
+    capacity: usize,
+    bytes: usize,
+    clock: u64,
+    entries: HashMap<ObjectId, Entry>,
+    // 最後に使った順
+    order: BTreeMap<u64, ObjectId>,
+    hits: u64,
+    misses: u64,
+    bypassed: u64,
+}
+
+impl ObjectCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            bytes: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+            bypassed: 0,
+        }
+    }
+
+    // 見つからなかったときは、読んだものの種類がわかってから miss か bypass を呼ぶ
+    pub fn get(&mut self, sha: &ObjectId) -> Option<(GitObjectKind, Rc<[u8]>)> {
+        let entry = self.entries.get_mut(sha)?;
+        self.hits += 1;
+        self.clock += 1;
+        self.order.remove(&entry.used);
+        self.order.insert(self.clock, *sha);
+        entry.used = self.clock;
+        Some((entry.kind.clone(), entry.data.clone()))
+    }
+
+    pub fn miss(&mut self) {
+        self.misses += 1;
+    }
+
+    pub fn bypass(&mut self) {
+        self.bypassed += 1;
+    }
+
+    pub fn contains(&self, sha: &ObjectId) -> bool {
+        self.entries.contains_key(sha)
+    }
+
+    pub fn insert(&mut self, sha: ObjectId, kind: GitObjectKind, data: Rc<[u8]>) {
+        // 1 つで上限を超えるものは入れない
+        if data.len() > self.capacity || self.entries.contains_key(&sha) {
+            return;
+        }
+        while self.bytes + data.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.bytes -= entry.data.len();
+        }
+        self.clock += 1;
+        self.bytes += data.len();
+        self.order.insert(self.clock, sha);
+        self.entries.insert(
+            sha,
+            Entry {
+                kind,
+                data,
+                used: self.clock,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            bypassed: self.bypassed,
+            entries: self.entries.len(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectCache;
+    use crate::{git_object::GitObjectKind, object_id::ObjectId};
+
+    #[test]
+    fn lru() {
+        let id = |i: u8| ObjectId::from_bytes(&[i; 20]).unwrap();
+        let mut cache = ObjectCache::new(10);
+        cache.insert(id(1), GitObjectKind::Tree, vec![0; 4].into());
+        cache.insert(id(2), GitObjectKind::Tree, vec![0; 4].into());
+        // 1 を使ったので、あふれたときは 2 が捨てられる
+        assert!(cache.get(&id(1)).is_some());
+        cache.insert(id(3), GitObjectKind::Commit, vec![0; 4].into());
+        assert!(cache.contains(&id(1)) && !cache.contains(&id(2)) && cache.contains(&id(3)));
+        assert!(cache.get(&id(2)).is_none());
+        cache.miss();
+        cache.bypass();
+        // 上限より大きいものは入れない
+        cache.insert(id(4), GitObjectKind::Blob, vec![0; 11].into());
+        assert!(!cache.contains(&id(4)));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.bypassed), (1, 1, 1));
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+    }
+}
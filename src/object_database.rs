@@ -0,0 +1,600 @@
+use crate::{
+    error::{Error, Result},
+    git_object::{deserialize_object, serialize_object, GitObject, GitObjectKind},
+    multi_pack_index::MultiPackIndex,
+    object_cache::{CacheStats, ObjectCache},
+    object_id::ObjectId,
+    object_stream::{object_hash_stream, write_stream, ObjectReader, MAX_PREALLOC},
+    pack::{pack_list, Pack},
+};
+use flate2::{write::ZlibEncoder, Compression};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// オブジェクトの保存先
+pub trait ObjectDatabase {
+    // ヘッダだけ読み、本体は必要になったときに読む
+    fn open(&self, sha: &ObjectId) -> Result<ObjectReader>;
+
+    // SHA-1 の計算と保存を 1 回の読み込みで行う
+    fn write_stream(
+        &self,
+        kind: &GitObjectKind,
+        size: usize,
+        body: &mut dyn Read,
+    ) -> Result<ObjectId>;
+
+    fn contains(&self, sha: &ObjectId) -> bool;
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>>;
+
+    // hex の前方一致で探す (小文字の hex を渡す)
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        Ok(self
+            .iter()?
+            .filter(|id| id.to_hex().starts_with(prefix))
+            .collect())
+    }
+
+    fn read(&self, sha: &ObjectId) -> Result<GitObject> {
+        let read = || {
+            let reader = self.open(sha)?;
+            let kind = reader.kind.clone();
+            deserialize_object(kind, &reader.read_to_vec()?)
+        };
+        read().map_err(|e| e.with_sha(sha))
+    }
+
+    // 中身をそのまま (パースせずに) 読む
+    fn read_raw(&self, sha: &ObjectId) -> Result<Vec<u8>> {
+        self.open(sha)?.read_to_vec().map_err(|e| e.with_sha(sha))
+    }
+
+    fn write(&self, obj: &GitObject) -> Result<ObjectId> {
+        let data = serialize_object(obj);
+        self.write_stream(&obj.kind(), data.len(), &mut data.as_slice())
+    }
+}
+
+// .git/objects/xx/yyyy... に zlib 圧縮して置く
+pub struct LooseObjectDatabase {
+    objects_dir: PathBuf,
+}
+
+impl LooseObjectDatabase {
+    pub fn new(objects_dir: PathBuf) -> Self {
+        Self { objects_dir }
+    }
+
+    // objects/xx/ の中のオブジェクト
+    fn fan_out(&self, dir_name: &str) -> Result<Vec<ObjectId>> {
+        let dir = self.objects_dir.join(dir_name);
+        let mut ids = Vec::new();
+        if !dir.is_dir() {
+            return Ok(ids);
+        }
+        for entry in fs::read_dir(dir)? {
+            let file_name = entry?.file_name();
+            let Some(rest) = file_name.to_str() else {
+                continue;
+            };
+            // 書き込み途中の一時ファイルなどは飛ばす
+            if let Ok(id) = format!("{}{}", dir_name, rest).parse() {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    // prune の猶予期間の判定に使う
+    pub fn modified(&self, sha: &ObjectId) -> Result<SystemTime> {
+        Ok(fs::metadata(self.objects_dir.join(sha.loose_path()))?.modified()?)
+    }
+
+    // loose object を消す (空になった objects/xx/ も消す)
+    pub fn remove(&self, sha: &ObjectId) -> Result<()> {
+        let path = self.objects_dir.join(sha.loose_path());
+        fs::remove_file(&path)?;
+        let _ = fs::remove_dir(path.parent().unwrap());
+        Ok(())
+    }
+}
+
+impl ObjectDatabase for LooseObjectDatabase {
+    fn open(&self, sha: &ObjectId) -> Result<ObjectReader> {
+        let path = self.objects_dir.join(sha.loose_path());
+        if !path.is_file() {
+            return Err(Error::ObjectNotFound(sha.to_hex()));
+        }
+        ObjectReader::from_loose(File::open(path)?).map_err(|e| e.with_sha(sha))
+    }
+
+    fn write_stream(
+        &self,
+        kind: &GitObjectKind,
+        size: usize,
+        body: &mut dyn Read,
+    ) -> Result<ObjectId> {
+        // SHA-1 は書き終わるまでわからないので一時ファイルに書いてから移動する
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let tmp_path = self
+            .objects_dir
+            .join(format!("tmp_obj_{}_{}", std::process::id(), nanos));
+        let f = File::create(&tmp_path)?;
+        let written = write_stream(
+            ZlibEncoder::new(f, Compression::default()),
+            kind,
+            size,
+            body,
+        )
+        .and_then(|(zipped, sha)| {
+            zipped.finish()?;
+            Ok(sha)
+        });
+        let sha = match written {
+            Ok(sha) => sha,
+            Err(e) => {
+                // 後始末に失敗しても元のエラーを返す
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+
+        let path = self.objects_dir.join(sha.loose_path());
+        if path.exists() {
+            let _ = fs::remove_file(&tmp_path);
+            // 書き直されたものはまだ使われているので prune されないように日時を更新する
+            // loose object は読み取り専用なので書き込みでは開かない。更新できなくても書き込みは成功している
+            let _ = File::open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+        } else {
+            let moved = fs::create_dir_all(path.parent().unwrap())
+                .and_then(|()| fs::rename(&tmp_path, &path));
+            if let Err(e) = moved {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e.into());
+            }
+        }
+        Ok(sha)
+    }
+
+    fn contains(&self, sha: &ObjectId) -> bool {
+        self.objects_dir.join(sha.loose_path()).is_file()
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids = Vec::new();
+        for i in 0..=0xff {
+            ids.extend(self.fan_out(&format!("{:02x}", i))?);
+        }
+        Ok(Box::new(ids.into_iter()))
+    }
+
+    // 先頭 2 文字のディレクトリだけを見る
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        if prefix.len() < 2 {
+            return Err(Error::InvalidObjectName(prefix.to_string()));
+        }
+        Ok(self
+            .fan_out(&prefix[..2])?
+            .into_iter()
+            .filter(|id| id.to_hex().starts_with(prefix))
+            .collect())
+    }
+}
+
+// .git/objects/pack/*.pack (読み込み専用)
+pub struct PackedObjectDatabase {
+    pack_dir: PathBuf,
+    // .idx は最初に必要になったときに読む
+    packs: OnceCell<PackSet>,
+}
+
+struct PackSet {
+    midx: Option<MultiPackIndex>,
+    // multi-pack-index に入っている pack は、その中の object を読むときに開く
+    midx_packs: Vec<OnceCell<Pack>>,
+    // multi-pack-index の後に作られた pack
+    packs: Vec<Pack>,
+}
+
+impl PackedObjectDatabase {
+    pub fn new(pack_dir: PathBuf) -> Self {
+        Self {
+            pack_dir,
+            packs: OnceCell::new(),
+        }
+    }
+
+    fn packs(&self) -> Result<&PackSet> {
+        if let Some(packs) = self.packs.get() {
+            return Ok(packs);
+        }
+        let idx_paths = pack_list(&self.pack_dir)?;
+        let mut midx = MultiPackIndex::open(&self.pack_dir).unwrap_or_else(|e| {
+            eprintln!("warning: ignoring multi-pack-index: {}", e);
+            None
+        });
+        // 消された pack を指していたら使わない
+        if let Some(m) = &midx {
+            let missing = m
+                .pack_names
+                .iter()
+                .find(|name| !idx_paths.contains(&self.pack_dir.join(name)));
+            if let Some(name) = missing {
+                eprintln!("warning: multi-pack-index refers to missing {}", name);
+                midx = None;
+            }
+        }
+        let pack_names = midx
+            .as_ref()
+            .map(|m| m.pack_names.as_slice())
+            .unwrap_or(&[]);
+        let packs = idx_paths
+            .iter()
+            .filter(|idx_path| {
+                let name = idx_path.file_name().unwrap().to_string_lossy();
+                !pack_names.iter().any(|n| *n == name)
+            })
+            .map(|idx_path| Pack::open(idx_path))
+            .collect::<Result<Vec<_>>>()?;
+        let midx_packs = pack_names.iter().map(|_| OnceCell::new()).collect();
+        Ok(self.packs.get_or_init(|| PackSet {
+            midx,
+            midx_packs,
+            packs,
+        }))
+    }
+
+    // object が入っている pack とその中での offset
+    fn find(&self, sha: &ObjectId) -> Result<Option<(&Pack, u64)>> {
+        let packs = self.packs()?;
+        if let Some(midx) = &packs.midx {
+            if let Some(i) = midx.lookup(sha) {
+                let (pack_id, offset) = midx.entry(i)?;
+                let cell = &packs.midx_packs[pack_id];
+                let pack = match cell.get() {
+                    Some(pack) => pack,
+                    None => {
+                        let pack = Pack::open(&self.pack_dir.join(&midx.pack_names[pack_id]))?;
+                        cell.get_or_init(|| pack)
+                    }
+                };
+                return Ok(Some((pack, offset)));
+            }
+        }
+        Ok(packs
+            .packs
+            .iter()
+            .find_map(|pack| pack.index.lookup(sha).map(|i| (pack, pack.index.offset(i)))))
+    }
+
+    // REF_DELTA の base は別の pack にあるかもしれない
+    fn read_base(&self, sha: &ObjectId) -> Result<(GitObjectKind, Vec<u8>)> {
+        let reader = self.open(sha)?;
+        let kind = reader.kind.clone();
+        Ok((kind, reader.read_to_vec()?))
+    }
+}
+
+impl ObjectDatabase for PackedObjectDatabase {
+    fn open(&self, sha: &ObjectId) -> Result<ObjectReader> {
+        let (pack, offset) = self.find(sha)?.ok_or(Error::ObjectNotFound(sha.to_hex()))?;
+        pack.open_at(offset, &|base| self.read_base(base))
+            .map_err(|e| e.with_sha(sha))
+    }
+
+    fn write_stream(
+        &self,
+        _kind: &GitObjectKind,
+        _size: usize,
+        _body: &mut dyn Read,
+    ) -> Result<ObjectId> {
+        Err(Error::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "packed objects are read-only",
+        )))
+    }
+
+    fn contains(&self, sha: &ObjectId) -> bool {
+        self.packs().is_ok_and(|packs| {
+            packs.midx.as_ref().is_some_and(|m| m.lookup(sha).is_some())
+                || packs
+                    .packs
+                    .iter()
+                    .any(|pack| pack.index.lookup(sha).is_some())
+        })
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let packs = self.packs()?;
+        Ok(Box::new(packs.midx.iter().flat_map(|m| m.ids()).chain(
+            packs.packs.iter().flat_map(|pack| pack.index.ids()),
+        )))
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let packs = self.packs()?;
+        Ok(packs
+            .midx
+            .iter()
+            .flat_map(|m| m.find_prefix(prefix))
+            .chain(
+                packs
+                    .packs
+                    .iter()
+                    .flat_map(|pack| pack.index.find_prefix(prefix)),
+            )
+            .collect())
+    }
+}
+
+// alternates の連鎖はこの深さまでたどる (git と同じ)
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+// objects_dir から読める別の objects ディレクトリ (extra と objects/info/alternates に書かれたもの)
+// alternates の中の相対パスはそのファイルを持つ objects ディレクトリから見たもの
+pub fn alternate_dirs(objects_dir: &Path, extra: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut seen = vec![fs::canonicalize(objects_dir).unwrap_or(objects_dir.to_path_buf())];
+    let mut dirs = Vec::new();
+    let mut pending = extra.iter().map(|dir| (dir.clone(), 0)).collect::<Vec<_>>();
+    pending.extend(read_alternates(objects_dir, 0)?);
+    // 書かれた順に見るので後ろから取り出す
+    pending.reverse();
+    while let Some((dir, depth)) = pending.pop() {
+        let Ok(dir) = fs::canonicalize(&dir) else {
+            eprintln!("warning: object directory {} does not exist", dir.display());
+            continue;
+        };
+        if seen.contains(&dir) {
+            continue;
+        }
+        seen.push(dir.clone());
+        if depth < MAX_ALTERNATE_DEPTH {
+            pending.extend(read_alternates(&dir, depth + 1)?.into_iter().rev());
+        } else {
+            eprintln!(
+                "warning: {}: ignoring alternate object stores, nesting too deep",
+                dir.display()
+            );
+        }
+        dirs.push(dir);
+    }
+    Ok(dirs)
+}
+
+fn read_alternates(objects_dir: &Path, depth: usize) -> Result<Vec<(PathBuf, usize)>> {
+    let content = match fs::read_to_string(objects_dir.join("info").join("alternates")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| (objects_dir.join(line), depth))
+        .collect())
+}
+
+// 複数の保存先を 1 つに見せる (読み込みは見つかったところから、書き込みは先頭へ)
+pub struct CombinedObjectDatabase {
+    stores: Vec<Box<dyn ObjectDatabase>>,
+}
+
+impl CombinedObjectDatabase {
+    pub fn new(stores: Vec<Box<dyn ObjectDatabase>>) -> Self {
+        Self { stores }
+    }
+}
+
+impl ObjectDatabase for CombinedObjectDatabase {
+    fn open(&self, sha: &ObjectId) -> Result<ObjectReader> {
+        self.stores
+            .iter()
+            .find(|store| store.contains(sha))
+            .ok_or(Error::ObjectNotFound(sha.to_hex()))?
+            .open(sha)
+    }
+
+    fn write_stream(
+        &self,
+        kind: &GitObjectKind,
+        size: usize,
+        body: &mut dyn Read,
+    ) -> Result<ObjectId> {
+        self.stores[0].write_stream(kind, size, body)
+    }
+
+    fn contains(&self, sha: &ObjectId) -> bool {
+        self.stores.iter().any(|store| store.contains(sha))
+    }
+
+    // loose と pack の両方にあるものは 1 つにまとめる
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids = BTreeSet::new();
+        for store in &self.stores {
+            ids.extend(store.iter()?);
+        }
+        Ok(Box::new(ids.into_iter()))
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let mut ids = BTreeSet::new();
+        for store in &self.stores {
+            ids.extend(store.find_prefix(prefix)?);
+        }
+        Ok(ids.into_iter().collect())
+    }
+}
+
+// 何度も読まれる commit, tree, tag を展開したまま覚えておく
+// blob は大きいことがあるので毎回読む
+pub struct CachedObjectDatabase {
+    inner: Box<dyn ObjectDatabase>,
+    cache: RefCell<ObjectCache>,
+}
+
+impl CachedObjectDatabase {
+    pub fn new(inner: Box<dyn ObjectDatabase>, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: RefCell::new(ObjectCache::new(capacity)),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+}
+
+impl ObjectDatabase for CachedObjectDatabase {
+    fn open(&self, sha: &ObjectId) -> Result<ObjectReader> {
+        if let Some((kind, data)) = self.cache.borrow_mut().get(sha) {
+            return Ok(ObjectReader::new(kind, data.len(), Cursor::new(data)));
+        }
+        let reader = self.inner.open(sha)?;
+        if reader.kind == GitObjectKind::Blob {
+            self.cache.borrow_mut().bypass();
+            return Ok(reader);
+        }
+        self.cache.borrow_mut().miss();
+        let kind = reader.kind.clone();
+        let data: Rc<[u8]> = reader.read_to_vec().map_err(|e| e.with_sha(sha))?.into();
+        self.cache
+            .borrow_mut()
+            .insert(*sha, kind.clone(), data.clone());
+        Ok(ObjectReader::new(kind, data.len(), Cursor::new(data)))
+    }
+
+    fn write_stream(
+        &self,
+        kind: &GitObjectKind,
+        size: usize,
+        body: &mut dyn Read,
+    ) -> Result<ObjectId> {
+        self.inner.write_stream(kind, size, body)
+    }
+
+    fn contains(&self, sha: &ObjectId) -> bool {
+        self.cache.borrow().contains(sha) || self.inner.contains(sha)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        self.inner.iter()
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        self.inner.find_prefix(prefix)
+    }
+}
+
+// GIT_TRACE_OBJECT_CACHE を設定するとコマンドの終わりにキャッシュの効き具合を表示する
+impl Drop for CachedObjectDatabase {
+    fn drop(&mut self) {
+        if std::env::var_os("GIT_TRACE_OBJECT_CACHE").is_some() {
+            eprintln!("object cache: {}", self.stats());
+        }
+    }
+}
+
+// テストなどでディスクを使わずにオブジェクトを扱う
+#[derive(Default)]
+pub struct MemoryObjectDatabase {
+    objects: RefCell<BTreeMap<ObjectId, (GitObjectKind, Vec<u8>)>>,
+}
+
+impl ObjectDatabase for MemoryObjectDatabase {
+    fn open(&self, sha: &ObjectId) -> Result<ObjectReader> {
+        let objects = self.objects.borrow();
+        let (kind, data) = objects
+            .get(sha)
+            .ok_or(Error::ObjectNotFound(sha.to_hex()))?;
+        Ok(ObjectReader::new(
+            kind.clone(),
+            data.len(),
+            Cursor::new(data.clone()),
+        ))
+    }
+
+    fn write_stream(
+        &self,
+        kind: &GitObjectKind,
+        size: usize,
+        body: &mut dyn Read,
+    ) -> Result<ObjectId> {
+        let mut data = Vec::with_capacity(size.min(MAX_PREALLOC));
+        body.take(size as u64 + 1).read_to_end(&mut data)?;
+        let sha = object_hash_stream(kind, size, data.as_slice())?;
+        self.objects
+            .borrow_mut()
+            .entry(sha)
+            .or_insert((kind.clone(), data));
+        Ok(sha)
+    }
+
+    fn contains(&self, sha: &ObjectId) -> bool {
+        self.objects.borrow().contains_key(sha)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let ids = self.objects.borrow().keys().copied().collect::<Vec<_>>();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryObjectDatabase, ObjectDatabase};
+    use crate::{
+        git_object::{object_peel, object_resolve, FileType, GitObject, GitObjectKind, TreeOject},
+        signature::Signature,
+    };
+
+    #[test]
+    fn memory_database() {
+        let odb = MemoryObjectDatabase::default();
+        let blob = odb
+            .write(&GitObject::Blob {
+                content: b"hi\n".to_vec(),
+            })
+            .unwrap();
+        // git hash-object と同じ SHA-1 になる
+        assert_eq!(blob.to_string(), "45b983be36b73c0788dc9cbcb76cbb80fc7bb057");
+        let tree = odb
+            .write(&GitObject::Tree(vec![TreeOject {
+                file_type: FileType::RegularFile,
+                permission: "0644".to_string(),
+                path: "a.txt".into(),
+                sha: blob,
+            }]))
+            .unwrap();
+        let tagger: Signature = "A <a@b> 1700000000 +0900".parse().unwrap();
+        let tag = odb
+            .write(&GitObject::Tag {
+                object: tree,
+                kind: GitObjectKind::Tree,
+                tag: "v1".into(),
+                tagger: Some(tagger),
+                extra_headers: vec![],
+                message: "tree tag\n".into(),
+            })
+            .unwrap();
+
+        assert!(odb.contains(&tag));
+        assert_eq!(odb.iter().unwrap().count(), 3);
+        assert_eq!(object_resolve(&odb, &tag.to_hex()[..6]).unwrap(), tag);
+        assert!(object_resolve(&odb, "0000000").is_err());
+        let (peeled, _) = object_peel(&odb, &tag, GitObjectKind::Tree).unwrap();
+        assert_eq!(peeled, tree);
+        assert!(object_peel(&odb, &tag, GitObjectKind::Commit).is_err());
+    }
+}
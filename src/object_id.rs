@@ -0,0 +1,90 @@
+use crate::error::{Error, Result};
+use std::{fmt, path::PathBuf, str::FromStr};
+
+// SHA-1 のオブジェクト ID (20 byte)
+// Default はすべて 0 の null object id
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId([u8; 20]);
+
+impl ObjectId {
+    pub const LEN: usize = 20;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; Self::LEN] = bytes
+            .try_into()
+            .map_err(|_| Error::InvalidObjectName(hex::encode(bytes)))?;
+        Ok(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::LEN] {
+        &self.0
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    // objects/ 以下の "xx/yyyy..." のパス
+    pub fn loose_path(self) -> PathBuf {
+        let hex = self.to_hex();
+        PathBuf::from(&hex[..2]).join(&hex[2..])
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidObjectName(s.to_string());
+        if s.len() != Self::LEN * 2 {
+            return Err(invalid());
+        }
+        Self::from_bytes(&hex::decode(s).map_err(|_| invalid())?)
+    }
+}
+
+// commit や tag のヘッダに書かれた hex
+impl TryFrom<bstr::BString> for ObjectId {
+    type Error = Error;
+
+    fn try_from(value: bstr::BString) -> Result<Self> {
+        std::str::from_utf8(&value)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(Error::corrupt(format!("Invalid object id {}", value)))
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({})", self.to_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectId;
+
+    #[test]
+    fn parse() {
+        let hex = "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed";
+        let id: ObjectId = hex.parse().unwrap();
+        assert_eq!(id.to_string(), hex);
+        assert_eq!(id.as_bytes()[0], 0x2a);
+        for invalid in [
+            "",
+            "a",
+            "2aae6c3",
+            &hex.replace('2', "g"),
+            &format!("{}0", hex),
+        ] {
+            assert!(invalid.parse::<ObjectId>().is_err());
+        }
+    }
+}
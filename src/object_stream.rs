@@ -0,0 +1,168 @@
+use crate::{
+    error::{Error, Result},
+    git_object::{parse_header, GitObjectKind},
+    object_id::ObjectId,
+};
+use flate2::read::ZlibDecoder;
+use sha1::{Digest, Sha1};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+// "<kind> <size>\0" のヘッダは数十 byte に収まる
+const MAX_HEADER_LEN: u64 = 32;
+// ヘッダに書かれた大きさは壊れているかもしれないので、先に確保するのはここまでにする
+pub const MAX_PREALLOC: usize = 1 << 20;
+
+// ヘッダだけ先に読み、本体は必要な分だけ展開しながら読む
+pub struct ObjectReader {
+    pub kind: GitObjectKind,
+    pub size: usize,
+    body: Box<dyn Read>,
+}
+
+impl ObjectReader {
+    pub fn new(kind: GitObjectKind, size: usize, body: impl Read + 'static) -> Self {
+        Self {
+            kind,
+            size,
+            body: Box::new(body.take(size as u64)),
+        }
+    }
+
+    // zlib 圧縮された "<kind> <size>\0<body>" (loose object) を開く
+    pub fn from_loose(compressed: impl Read + 'static) -> Result<Self> {
+        let mut inflated = BufReader::new(ZlibDecoder::new(compressed));
+        let mut header = Vec::new();
+        (&mut inflated)
+            .take(MAX_HEADER_LEN)
+            .read_until(0, &mut header)
+            .map_err(corrupt_stream)?;
+        let (kind, size, _) = parse_header(&header)?;
+        Ok(Self::new(kind, size, inflated))
+    }
+
+    // 本体をすべて読み、ヘッダのサイズと一致するか確かめる
+    pub fn read_to_vec(mut self) -> Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(self.size.min(MAX_PREALLOC));
+        self.body
+            .read_to_end(&mut content)
+            .map_err(corrupt_stream)?;
+        if self.size != content.len() {
+            return Err(Error::corrupt(format!(
+                "Size mismatch: expected {} bytes, got {}",
+                self.size,
+                content.len()
+            )));
+        }
+        Ok(content)
+    }
+}
+
+// zlib の展開に失敗したものは壊れたオブジェクトとして扱う
+pub fn corrupt_stream(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => {
+            Error::corrupt(e.to_string())
+        }
+        _ => Error::Io(e),
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+// 書き込んだバイト列の SHA-1 を同時に計算する
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha1,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha1::new(),
+        }
+    }
+
+    pub fn finish(self) -> (W, ObjectId) {
+        let sha = ObjectId::from_bytes(&self.hasher.finalize()).unwrap();
+        (self.inner, sha)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// ヘッダを付けて body を w に流し込み、オブジェクトの SHA-1 を返す
+pub fn write_stream<W: Write>(
+    w: W,
+    kind: &GitObjectKind,
+    size: usize,
+    body: impl Read,
+) -> Result<(W, ObjectId)> {
+    let mut w = HashWriter::new(w);
+    write!(w, "{} {}\0", kind.as_str(), size)?;
+    // size より長い入力も検出できるよう 1 byte 余分に読む
+    let copied = io::copy(&mut body.take(size as u64 + 1), &mut w)?;
+    if copied != size as u64 {
+        return Err(Error::corrupt(format!(
+            "Size mismatch: expected {} bytes, got {}",
+            size, copied
+        )));
+    }
+    Ok(w.finish())
+}
+
+// 保存せずに SHA-1 だけを求める
+pub fn object_hash_stream(kind: &GitObjectKind, size: usize, body: impl Read) -> Result<ObjectId> {
+    let (_, sha) = write_stream(io::sink(), kind, size, body)?;
+    Ok(sha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{object_hash_stream, write_stream, ObjectReader};
+    use crate::git_object::GitObjectKind;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Read;
+
+    #[test]
+    fn stream_round_trip() {
+        let content = b"hello world".repeat(1000);
+        let (zipped, sha) = write_stream(
+            ZlibEncoder::new(Vec::new(), Compression::default()),
+            &GitObjectKind::Blob,
+            content.len(),
+            content.as_slice(),
+        )
+        .unwrap();
+        assert_eq!(
+            object_hash_stream(&GitObjectKind::Blob, content.len(), content.as_slice()).unwrap(),
+            sha
+        );
+
+        let mut reader =
+            ObjectReader::from_loose(std::io::Cursor::new(zipped.finish().unwrap())).unwrap();
+        assert_eq!(reader.kind, GitObjectKind::Blob);
+        assert_eq!(reader.size, content.len());
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, content);
+
+        // 宣言したサイズと中身の長さが違えばエラー
+        assert!(object_hash_stream(&GitObjectKind::Blob, 3, &b"abcd"[..]).is_err());
+        assert!(object_hash_stream(&GitObjectKind::Blob, 5, &b"abcd"[..]).is_err());
+    }
+}
@@ -0,0 +1,993 @@
+use crate::{
+    delta::{apply_delta, DeltaIndex},
+    error::{Error, Result},
+    git_object::GitObjectKind,
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+    object_stream::{corrupt_stream, object_hash_stream, HashWriter, ObjectReader, MAX_PREALLOC},
+};
+use bstr::{BString, ByteSlice};
+use flate2::{bufread::ZlibDecoder, write::ZlibEncoder, Compression, Crc, CrcReader};
+use sha1::{Digest, Sha1};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const IDX_MAGIC: &[u8] = b"\xfftOc";
+const IDX_HEADER_LEN: usize = 8 + 256 * 4;
+const OFS_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+// 壊れた pack で REF_DELTA が循環しても止まるようにする
+const MAX_DELTA_DEPTH: usize = 10000;
+
+fn be32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn be64(data: &[u8], pos: usize) -> u64 {
+    u64::from_be_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+// .idx (version 2): fanout, SHA-1 の一覧, CRC32, offset, 大きな offset, checksum
+pub struct PackIndex {
+    fanout: [u32; 256],
+    names: Vec<u8>,
+    crc32: Vec<u32>,
+    offsets: Vec<u64>,
+    pub pack_checksum: ObjectId,
+}
+
+impl PackIndex {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < IDX_HEADER_LEN || &data[..4] != IDX_MAGIC {
+            return Err(Error::corrupt("Unsupported pack index"));
+        }
+        let version = be32(data, 4);
+        if version != 2 {
+            return Err(Error::corrupt(format!(
+                "Unsupported pack index version {}",
+                version
+            )));
+        }
+
+        let mut fanout = [0u32; 256];
+        for (i, n) in fanout.iter_mut().enumerate() {
+            *n = be32(data, 8 + i * 4);
+        }
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err(Error::corrupt("Pack index fanout is not monotonic"));
+        }
+
+        let count = fanout[255] as usize;
+        let crc_start = IDX_HEADER_LEN + count * 20;
+        let offsets_start = crc_start + count * 4;
+        let large_start = offsets_start + count * 4;
+        if data.len() < large_start + 40 {
+            return Err(Error::corrupt("Truncated pack index"));
+        }
+        let large_end = data.len() - 40;
+
+        let mut offsets = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = be32(data, offsets_start + i * 4);
+            if offset & 0x8000_0000 == 0 {
+                offsets.push(offset as u64);
+                continue;
+            }
+            // 最上位 bit が立っていれば 64 bit offset の表を引く
+            let pos = large_start + (offset & 0x7fff_ffff) as usize * 8;
+            if pos + 8 > large_end {
+                return Err(Error::corrupt("Pack index large offset out of range"));
+            }
+            offsets.push(be64(data, pos));
+        }
+
+        Ok(Self {
+            fanout,
+            names: data[IDX_HEADER_LEN..crc_start].to_vec(),
+            crc32: (0..count).map(|i| be32(data, crc_start + i * 4)).collect(),
+            offsets,
+            pack_checksum: ObjectId::from_bytes(&data[large_end..large_end + 20])?,
+        })
+    }
+
+    // 末尾の 20 byte がそれより前の SHA-1 になっているか
+    pub fn checksum_ok(data: &[u8]) -> bool {
+        data.len() >= ObjectId::LEN
+            && Sha1::digest(&data[..data.len() - ObjectId::LEN])[..]
+                == data[data.len() - ObjectId::LEN..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    fn name(&self, i: usize) -> &[u8] {
+        &self.names[i * 20..(i + 1) * 20]
+    }
+
+    pub fn id(&self, i: usize) -> ObjectId {
+        ObjectId::from_bytes(self.name(i)).unwrap()
+    }
+
+    pub fn offset(&self, i: usize) -> u64 {
+        self.offsets[i]
+    }
+
+    pub fn crc32(&self, i: usize) -> u32 {
+        self.crc32[i]
+    }
+
+    // 先頭 1 byte が first のものの範囲
+    fn bucket(&self, first: u8) -> (usize, usize) {
+        let lo = match first {
+            0 => 0,
+            n => self.fanout[n as usize - 1] as usize,
+        };
+        (lo, self.fanout[first as usize] as usize)
+    }
+
+    pub fn lookup(&self, sha: &ObjectId) -> Option<usize> {
+        let (mut lo, mut hi) = self.bucket(sha.as_bytes()[0]);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.name(mid).cmp(sha.as_bytes()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        (0..self.len()).map(|i| self.id(i))
+    }
+
+    // hex の前方一致 (先頭 2 文字で fanout の範囲に絞る)
+    pub fn find_prefix(&self, prefix: &str) -> Vec<ObjectId> {
+        let Some(first) = prefix.get(..2).and_then(|s| u8::from_str_radix(s, 16).ok()) else {
+            return Vec::new();
+        };
+        let (lo, hi) = self.bucket(first);
+        (lo..hi)
+            .map(|i| self.id(i))
+            .filter(|id| id.to_hex().starts_with(prefix))
+            .collect()
+    }
+}
+
+pub enum PackEntryKind {
+    Base(GitObjectKind),
+    // 同じ pack 内の base の offset
+    OfsDelta(u64),
+    RefDelta(ObjectId),
+}
+
+// pack 内の 1 エントリのヘッダ (size は展開後のサイズ、delta なら delta 自体のサイズ)
+pub struct PackEntry {
+    pub offset: u64,
+    pub kind: PackEntryKind,
+    pub size: usize,
+    pub data_offset: u64,
+}
+
+fn read_byte(reader: &mut impl Read) -> Result<u8> {
+    let mut b = [0];
+    reader.read_exact(&mut b).map_err(corrupt_stream)?;
+    Ok(b[0])
+}
+
+// "PACK", version, オブジェクト数
+fn pack_header_count(header: &[u8; 12], pack_path: &Path) -> Result<usize> {
+    if &header[..4] != b"PACK" {
+        return Err(Error::corrupt(format!(
+            "{} is not a packfile",
+            pack_path.display()
+        )));
+    }
+    let version = be32(header, 4);
+    if version != 2 && version != 3 {
+        return Err(Error::corrupt(format!(
+            "Unsupported pack version {}",
+            version
+        )));
+    }
+    Ok(be32(header, 8) as usize)
+}
+
+// delta の base を pack の外から読む
+pub type BaseResolver<'a> = &'a dyn Fn(&ObjectId) -> Result<(GitObjectKind, Vec<u8>)>;
+
+pub struct Pack {
+    pub pack_path: PathBuf,
+    pub index: PackIndex,
+}
+
+impl Pack {
+    // xxx.idx と同じ名前の xxx.pack を開く
+    pub fn open(idx_path: &Path) -> Result<Self> {
+        let index = PackIndex::parse(&fs::read(idx_path)?)?;
+        let pack_path = idx_path.with_extension("pack");
+
+        let mut f = File::open(&pack_path)?;
+        let mut header = [0u8; 12];
+        f.read_exact(&mut header).map_err(corrupt_stream)?;
+        // 末尾の checksum が .idx に記録されたものと同じか (別の pack の .idx でないか)
+        let mut trailer = [0u8; ObjectId::LEN];
+        f.seek(SeekFrom::End(-(ObjectId::LEN as i64)))?;
+        f.read_exact(&mut trailer).map_err(corrupt_stream)?;
+        if trailer != *index.pack_checksum.as_bytes() {
+            return Err(Error::corrupt(format!(
+                "{} does not match its index",
+                pack_path.display()
+            )));
+        }
+        let count = pack_header_count(&header, &pack_path)?;
+        if count != index.len() {
+            return Err(Error::corrupt(format!(
+                "{} has {} objects but its index has {}",
+                pack_path.display(),
+                count,
+                index.len()
+            )));
+        }
+        Ok(Self { pack_path, index })
+    }
+
+    pub fn reader(&self) -> Result<BufReader<File>> {
+        Ok(BufReader::new(File::open(&self.pack_path)?))
+    }
+
+    // 型と展開後のサイズ (7 bit ずつ) に続いて、delta なら base の位置がある
+    pub fn read_entry(reader: &mut BufReader<File>, offset: u64) -> Result<PackEntry> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut c = read_byte(reader)?;
+        let mut len = 1;
+        let type_no = (c >> 4) & 7;
+        let mut size = (c & 0x0f) as usize;
+        let mut shift = 4;
+        while c & 0x80 != 0 {
+            if shift > 57 {
+                return Err(Error::corrupt("Pack entry size too large"));
+            }
+            c = read_byte(reader)?;
+            len += 1;
+            size |= ((c & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+
+        let kind = match type_no {
+            OFS_DELTA => {
+                // 続きがあるたびに +1 してから 7 bit ずらす (git 独自の可変長)
+                let mut c = read_byte(reader)?;
+                len += 1;
+                let mut distance = (c & 0x7f) as u64;
+                while c & 0x80 != 0 {
+                    if distance >= 1 << 56 {
+                        return Err(Error::corrupt("Delta base offset too large"));
+                    }
+                    c = read_byte(reader)?;
+                    len += 1;
+                    distance = ((distance + 1) << 7) | (c & 0x7f) as u64;
+                }
+                if distance == 0 || distance > offset {
+                    return Err(Error::corrupt("Delta base offset out of range"));
+                }
+                PackEntryKind::OfsDelta(offset - distance)
+            }
+            REF_DELTA => {
+                let mut id = [0u8; ObjectId::LEN];
+                reader.read_exact(&mut id).map_err(corrupt_stream)?;
+                len += ObjectId::LEN as u64;
+                PackEntryKind::RefDelta(ObjectId::from_bytes(&id)?)
+            }
+            n => PackEntryKind::Base(
+                GitObjectKind::from_pack_type(n)
+                    .ok_or_else(|| Error::corrupt(format!("Unknown pack object type {}", n)))?,
+            ),
+        };
+        Ok(PackEntry {
+            offset,
+            kind,
+            size,
+            data_offset: offset + len,
+        })
+    }
+
+    // エントリの zlib 部分を展開し、圧縮された部分の長さも返す
+    pub fn inflate(reader: &mut BufReader<File>, entry: &PackEntry) -> Result<(Vec<u8>, u64)> {
+        reader.seek(SeekFrom::Start(entry.data_offset))?;
+        let mut data = Vec::with_capacity(entry.size.min(MAX_PREALLOC));
+        let mut decoder = ZlibDecoder::new(reader);
+        // zlib の終わりまで読めば total_in が圧縮後の長さになる
+        (&mut decoder)
+            .take(entry.size as u64 + 1)
+            .read_to_end(&mut data)
+            .map_err(corrupt_stream)?;
+        if data.len() != entry.size {
+            return Err(Error::corrupt(format!(
+                "Pack entry at {} has a wrong size",
+                entry.offset
+            )));
+        }
+        Ok((data, decoder.total_in()))
+    }
+
+    // delta を base までたどり、base から順に適用していく
+    // REF_DELTA の base は lookup で pack 内の offset を探し、なければ external で読む
+    pub fn resolve(
+        reader: &mut BufReader<File>,
+        offset: u64,
+        lookup: &dyn Fn(&ObjectId) -> Option<u64>,
+        external: BaseResolver,
+    ) -> Result<(GitObjectKind, Vec<u8>)> {
+        let mut chain = Vec::new();
+        let mut offset = offset;
+        let (kind, mut data) = loop {
+            if chain.len() > MAX_DELTA_DEPTH {
+                return Err(Error::corrupt("Delta chain too deep"));
+            }
+            let entry = Self::read_entry(reader, offset)?;
+            offset = match &entry.kind {
+                PackEntryKind::Base(kind) => {
+                    break (kind.clone(), Self::inflate(reader, &entry)?.0)
+                }
+                PackEntryKind::OfsDelta(base) => *base,
+                PackEntryKind::RefDelta(base) => match lookup(base) {
+                    Some(offset) => offset,
+                    None => {
+                        let base = external(base)?;
+                        chain.push(entry);
+                        break base;
+                    }
+                },
+            };
+            chain.push(entry);
+        };
+        for entry in chain.iter().rev() {
+            data = apply_delta(&data, &Self::inflate(reader, entry)?.0)?;
+        }
+        Ok((kind, data))
+    }
+
+    // delta をたどって base の型だけを調べる (中身は展開しない)
+    pub fn kind_at(&self, reader: &mut BufReader<File>, offset: u64) -> Result<GitObjectKind> {
+        let mut offset = offset;
+        for _ in 0..=MAX_DELTA_DEPTH {
+            offset = match Self::read_entry(reader, offset)?.kind {
+                PackEntryKind::Base(kind) => return Ok(kind),
+                PackEntryKind::OfsDelta(base) => base,
+                PackEntryKind::RefDelta(base) => self
+                    .index
+                    .lookup(&base)
+                    .map(|i| self.index.offset(i))
+                    .ok_or_else(|| {
+                        Error::corrupt(format!("Delta base {} is not in the pack", base))
+                    })?,
+            };
+        }
+        Err(Error::corrupt("Delta chain too deep"))
+    }
+
+    pub fn read_at(
+        &self,
+        reader: &mut BufReader<File>,
+        offset: u64,
+        external: BaseResolver,
+    ) -> Result<(GitObjectKind, Vec<u8>)> {
+        let lookup = |sha: &ObjectId| self.index.lookup(sha).map(|i| self.index.offset(i));
+        Self::resolve(reader, offset, &lookup, external)
+    }
+
+    pub fn open_at(&self, offset: u64, external: BaseResolver) -> Result<ObjectReader> {
+        let mut reader = self.reader()?;
+        let entry = Self::read_entry(&mut reader, offset)?;
+        if let PackEntryKind::Base(kind) = entry.kind {
+            // delta でなければ展開しながら読める
+            reader.seek(SeekFrom::Start(entry.data_offset))?;
+            return Ok(ObjectReader::new(
+                kind,
+                entry.size,
+                ZlibDecoder::new(reader),
+            ));
+        }
+        let (kind, data) = self.read_at(&mut reader, offset, external)?;
+        Ok(ObjectReader::new(kind, data.len(), Cursor::new(data)))
+    }
+}
+
+// pack ディレクトリの中の .idx を探す
+pub fn pack_list(pack_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let entries = match fs::read_dir(pack_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(paths),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "idx") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// .idx に書く 1 オブジェクト分の情報
+pub struct IndexEntry {
+    pub sha: ObjectId,
+    pub offset: u64,
+    pub crc32: u32,
+}
+
+// エントリの CRC32 と長さを数えながら書く
+struct EntryWriter<'a, W: Write> {
+    inner: &'a mut HashWriter<W>,
+    crc: Crc,
+    len: u64,
+}
+
+impl<W: Write> Write for EntryWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// 型と展開後のサイズを 4 bit + 7 bit ずつで書く
+fn entry_header(type_no: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(type_no << 4) | (size & 0x0f) as u8];
+    let mut rest = size >> 4;
+    while rest > 0 {
+        *header.last_mut().unwrap() |= 0x80;
+        header.push((rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    header
+}
+
+// "PACK", version, オブジェクト数のヘッダに続けてエントリを書き、最後に SHA-1 を付ける
+pub struct PackWriter<W: Write> {
+    out: HashWriter<W>,
+    offset: u64,
+    count: u32,
+    entries: Vec<IndexEntry>,
+}
+
+impl<W: Write> PackWriter<W> {
+    pub fn new(w: W, count: u32) -> Result<Self> {
+        let mut out = HashWriter::new(w);
+        out.write_all(b"PACK")?;
+        out.write_all(&2u32.to_be_bytes())?;
+        out.write_all(&count.to_be_bytes())?;
+        Ok(Self {
+            out,
+            offset: 12,
+            count,
+            entries: Vec::new(),
+        })
+    }
+
+    // header に続けて body を圧縮しながら書き、エントリの offset を返す
+    fn write_entry(
+        &mut self,
+        sha: ObjectId,
+        header: &[u8],
+        size: usize,
+        body: impl Read,
+    ) -> Result<u64> {
+        let offset = self.offset;
+        let mut w = EntryWriter {
+            inner: &mut self.out,
+            crc: Crc::new(),
+            len: 0,
+        };
+        w.write_all(header)?;
+        let mut zipped = ZlibEncoder::new(w, Compression::default());
+        let copied = io::copy(&mut body.take(size as u64 + 1), &mut zipped)?;
+        if copied != size as u64 {
+            return Err(Error::corrupt(format!(
+                "Size mismatch: expected {} bytes, got {}",
+                size, copied
+            ))
+            .with_sha(&sha));
+        }
+        let w = zipped.finish()?;
+        self.offset += w.len;
+        self.entries.push(IndexEntry {
+            sha,
+            offset,
+            crc32: w.crc.sum(),
+        });
+        Ok(offset)
+    }
+
+    pub fn write_object(
+        &mut self,
+        sha: ObjectId,
+        kind: &GitObjectKind,
+        size: usize,
+        body: impl Read,
+    ) -> Result<u64> {
+        self.write_entry(sha, &entry_header(kind.pack_type(), size), size, body)
+    }
+
+    // 先に書いた base_offset のエントリとの差分として書く
+    pub fn write_ofs_delta(
+        &mut self,
+        sha: ObjectId,
+        base_offset: u64,
+        delta: &[u8],
+    ) -> Result<u64> {
+        let mut header = entry_header(OFS_DELTA, delta.len());
+        // read_entry の逆 (続きがあるたびに 1 引いてから 7 bit ずらす)
+        let mut distance = self.offset - base_offset;
+        let mut encoded = vec![(distance & 0x7f) as u8];
+        distance >>= 7;
+        while distance > 0 {
+            distance -= 1;
+            encoded.push(0x80 | (distance & 0x7f) as u8);
+            distance >>= 7;
+        }
+        header.extend(encoded.iter().rev());
+        self.write_entry(sha, &header, delta.len(), delta)
+    }
+
+    pub fn finish(self) -> Result<(W, Vec<IndexEntry>, ObjectId)> {
+        if self.entries.len() != self.count as usize {
+            return Err(Error::corrupt(format!(
+                "Pack header says {} objects but {} were written",
+                self.count,
+                self.entries.len()
+            )));
+        }
+        let (mut w, checksum) = self.out.finish();
+        w.write_all(checksum.as_bytes())?;
+        Ok((w, self.entries, checksum))
+    }
+}
+
+// .idx (version 2) を書く。entries は SHA-1 順に並べ替える
+pub fn write_index<W: Write>(
+    w: W,
+    entries: &mut [IndexEntry],
+    pack_checksum: &ObjectId,
+) -> Result<(W, ObjectId)> {
+    entries.sort_by_key(|e| e.sha);
+    let mut out = HashWriter::new(w);
+    out.write_all(IDX_MAGIC)?;
+    out.write_all(&2u32.to_be_bytes())?;
+
+    let mut fanout = [0u32; 256];
+    for e in entries.iter() {
+        fanout[e.sha.as_bytes()[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for n in fanout.iter_mut() {
+        total += *n;
+        out.write_all(&total.to_be_bytes())?;
+    }
+    for e in entries.iter() {
+        out.write_all(e.sha.as_bytes())?;
+    }
+    for e in entries.iter() {
+        out.write_all(&e.crc32.to_be_bytes())?;
+    }
+    // 31 bit に収まらない offset は後ろの 64 bit の表に逃がす
+    let mut large = Vec::new();
+    for e in entries.iter() {
+        if e.offset < 0x8000_0000 {
+            out.write_all(&(e.offset as u32).to_be_bytes())?;
+        } else {
+            out.write_all(&(0x8000_0000 | large.len() as u32).to_be_bytes())?;
+            large.push(e.offset);
+        }
+    }
+    for offset in large {
+        out.write_all(&offset.to_be_bytes())?;
+    }
+    out.write_all(pack_checksum.as_bytes())?;
+
+    let (mut w, checksum) = out.finish();
+    w.write_all(checksum.as_bytes())?;
+    Ok((w, checksum))
+}
+
+// 書き終わってから rename するための一時ファイル名
+pub fn tmp_path(dir: &Path, prefix: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    dir.join(format!("{}_{}_{}", prefix, std::process::id(), nanos))
+}
+
+// 一時ファイルに書いてから idx_path に移す
+pub fn write_index_file(
+    idx_path: &Path,
+    entries: &mut [IndexEntry],
+    pack_checksum: &ObjectId,
+) -> Result<()> {
+    let tmp_idx = tmp_path(idx_path.parent().unwrap_or(Path::new(".")), "tmp_idx");
+    let mut write = || -> Result<()> {
+        let (w, _) = write_index(
+            BufWriter::new(File::create(&tmp_idx)?),
+            entries,
+            pack_checksum,
+        )?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_idx, idx_path)?;
+        Ok(())
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_idx);
+    })
+}
+
+// delta を作るときの設定 (既定値は git の pack.window, pack.depth と同じ)
+pub struct DeltaOptions {
+    // 何個前までのオブジェクトを base の候補にするか
+    pub window: usize,
+    // delta の連鎖の最大の長さ
+    pub depth: usize,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            depth: 50,
+        }
+    }
+}
+
+// これより大きいものは delta にせずそのまま書く (git の core.bigFileThreshold)
+const BIG_FILE_THRESHOLD: usize = 512 * 1024 * 1024;
+// 小さすぎるものは delta にしても得にならない
+const MIN_DELTA_SIZE: usize = 64;
+
+// 同じ種類で、ファイル名が同じで、サイズの近いものが並ぶようにして window の中から base を選ぶ
+// 戻り値は delta にするオブジェクトの (base, delta)
+fn plan_deltas(
+    odb: &dyn ObjectDatabase,
+    objects: &[(ObjectId, BString)],
+    options: &DeltaOptions,
+) -> Result<HashMap<ObjectId, (ObjectId, Vec<u8>)>> {
+    let mut candidates = Vec::new();
+    for (sha, path) in objects {
+        let reader = odb.open(sha)?;
+        if (MIN_DELTA_SIZE..=BIG_FILE_THRESHOLD).contains(&reader.size) {
+            let name = path.rsplit_str("/").next().unwrap_or_default().to_vec();
+            candidates.push((
+                reader.kind.pack_type(),
+                name,
+                path,
+                Reverse(reader.size),
+                *sha,
+            ));
+        }
+    }
+    candidates.sort();
+
+    let mut deltas = HashMap::new();
+    let mut depths = HashMap::new();
+    let mut window: VecDeque<(u8, ObjectId, DeltaIndex)> = VecDeque::new();
+    for (type_no, _, _, _, sha) in candidates {
+        let target = odb.read_raw(&sha)?;
+        // 半分以下にならなければそのまま書く
+        let mut best: Option<(ObjectId, Vec<u8>)> = None;
+        let mut max_size = target.len() / 2 - ObjectId::LEN;
+        for (base_type, base_sha, index) in window.iter().rev() {
+            let base_depth = depths.get(base_sha).copied().unwrap_or(0);
+            if *base_type != type_no || base_depth >= options.depth {
+                continue;
+            }
+            // 大きくなる分は insert になるので、それだけで max_size を超えるなら試さない
+            if target.len().saturating_sub(index.base().len()) >= max_size {
+                continue;
+            }
+            if let Some(delta) = index.delta(&target, max_size) {
+                max_size = delta.len().saturating_sub(1);
+                best = Some((*base_sha, delta));
+            }
+        }
+        if let Some((base, delta)) = best {
+            depths.insert(sha, depths.get(&base).copied().unwrap_or(0) + 1);
+            deltas.insert(sha, (base, delta));
+        }
+
+        if options.window == 0 {
+            continue;
+        }
+        if window.len() == options.window {
+            window.pop_front();
+        }
+        window.push_back((type_no, sha, DeltaIndex::new(target)));
+    }
+    Ok(deltas)
+}
+
+// base を先に書いてから delta を書く
+fn write_planned<W: Write>(
+    odb: &dyn ObjectDatabase,
+    pack: &mut PackWriter<W>,
+    deltas: &HashMap<ObjectId, (ObjectId, Vec<u8>)>,
+    offsets: &mut HashMap<ObjectId, u64>,
+    sha: &ObjectId,
+) -> Result<u64> {
+    if let Some(offset) = offsets.get(sha) {
+        return Ok(*offset);
+    }
+    let offset = match deltas.get(sha) {
+        Some((base, delta)) => {
+            let base_offset = write_planned(odb, pack, deltas, offsets, base)?;
+            pack.write_ofs_delta(*sha, base_offset, delta)?
+        }
+        None => {
+            let reader = odb.open(sha)?;
+            let (kind, size) = (reader.kind.clone(), reader.size);
+            pack.write_object(*sha, &kind, size, reader)?
+        }
+    };
+    offsets.insert(*sha, offset);
+    Ok(offset)
+}
+
+// objects (id と tree の中でのパス) を 1 つの pack にまとめ、pack-<checksum>.pack と .idx を作る
+pub fn pack_objects(
+    odb: &dyn ObjectDatabase,
+    pack_dir: &Path,
+    objects: &[(ObjectId, BString)],
+    options: &DeltaOptions,
+) -> Result<PathBuf> {
+    fs::create_dir_all(pack_dir)?;
+    let tmp_pack = tmp_path(pack_dir, "tmp_pack");
+    let write = || -> Result<PathBuf> {
+        let deltas = plan_deltas(odb, objects, options)?;
+        let mut pack = PackWriter::new(
+            BufWriter::new(File::create(&tmp_pack)?),
+            objects.len() as u32,
+        )?;
+        let mut offsets = HashMap::new();
+        for (sha, _) in objects {
+            write_planned(odb, &mut pack, &deltas, &mut offsets, sha)?;
+        }
+        let (w, mut entries, checksum) = pack.finish()?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        // .idx があれば読まれるので .pack を先に置く
+        let pack_path = pack_dir.join(format!("pack-{}.pack", checksum));
+        fs::rename(&tmp_pack, &pack_path)?;
+        write_index_file(&pack_path.with_extension("idx"), &mut entries, &checksum)?;
+        Ok(pack_path)
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_pack);
+    })
+}
+
+// index-pack と verify-pack のための、pack だけからわかるエントリの情報
+pub struct ScannedEntry {
+    pub sha: ObjectId,
+    pub kind: GitObjectKind,
+    // pack に書かれたサイズ (delta なら delta 自体のサイズ)
+    pub size: usize,
+    pub packed_size: u64,
+    pub offset: u64,
+    pub crc32: u32,
+    pub depth: usize,
+    pub base: Option<ObjectId>,
+}
+
+// pack を .idx なしで先頭から読み、checksum を確かめてすべてのオブジェクトの SHA-1 を求める
+// 戻り値は offset 順のエントリと pack の checksum
+pub fn scan_pack(pack_path: &Path) -> Result<(Vec<ScannedEntry>, ObjectId)> {
+    let len = fs::metadata(pack_path)?.len();
+    if len < 12 + ObjectId::LEN as u64 {
+        return Err(Error::corrupt(format!(
+            "{} is too short",
+            pack_path.display()
+        )));
+    }
+    let end = len - ObjectId::LEN as u64;
+    let mut reader = BufReader::new(File::open(pack_path)?);
+    let mut hasher = HashWriter::new(io::sink());
+    io::copy(&mut (&mut reader).take(end), &mut hasher)?;
+    let (_, checksum) = hasher.finish();
+    let mut trailer = [0u8; ObjectId::LEN];
+    reader.read_exact(&mut trailer)?;
+    if trailer != *checksum.as_bytes() {
+        return Err(Error::corrupt(format!(
+            "{} has a wrong checksum",
+            pack_path.display()
+        )));
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    let count = pack_header_count(&header, pack_path)?;
+
+    // delta 以外はここで SHA-1 がわかる
+    let mut scanned = Vec::with_capacity(count);
+    let mut resolved: Vec<Option<(ObjectId, GitObjectKind, usize)>> = Vec::with_capacity(count);
+    let mut offset = 12;
+    for _ in 0..count {
+        if offset >= end {
+            return Err(Error::corrupt(format!(
+                "{} is truncated",
+                pack_path.display()
+            )));
+        }
+        let entry = Pack::read_entry(&mut reader, offset)?;
+        let (data, compressed_len) = Pack::inflate(&mut reader, &entry)?;
+        let next = entry.data_offset + compressed_len;
+        resolved.push(match &entry.kind {
+            PackEntryKind::Base(kind) => Some((
+                object_hash_stream(kind, data.len(), data.as_slice())?,
+                kind.clone(),
+                0,
+            )),
+            _ => None,
+        });
+        // CRC32 はヘッダも含めたエントリのバイト列に対して計算する
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut crc = CrcReader::new((&mut reader).take(next - offset));
+        io::copy(&mut crc, &mut io::sink())?;
+        scanned.push((entry, next - offset, crc.crc().sum()));
+        offset = next;
+    }
+    if offset != end {
+        return Err(Error::corrupt(format!(
+            "{} has garbage after the last object",
+            pack_path.display()
+        )));
+    }
+
+    // それぞれの base を使う delta の一覧 (OFS_DELTA は offset で、REF_DELTA は SHA-1 で引く)
+    let mut ofs_children: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut ref_children: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+    for (i, (entry, _, _)) in scanned.iter().enumerate() {
+        match &entry.kind {
+            PackEntryKind::OfsDelta(base) => {
+                if scanned
+                    .binary_search_by_key(base, |(e, _, _)| e.offset)
+                    .is_err()
+                {
+                    return Err(Error::corrupt(format!(
+                        "Delta at {} points to {} which is not an object",
+                        entry.offset, base
+                    )));
+                }
+                ofs_children.entry(*base).or_default().push(i);
+            }
+            PackEntryKind::RefDelta(base) => ref_children.entry(*base).or_default().push(i),
+            PackEntryKind::Base(_) => {}
+        }
+    }
+    let mut take_children = |i: usize, sha: &ObjectId| {
+        let mut children = ofs_children
+            .remove(&scanned[i].0.offset)
+            .unwrap_or_default();
+        children.extend(ref_children.remove(sha).unwrap_or_default());
+        children
+    };
+
+    // delta でないものから順に、それを base にする delta を展開していく
+    // どのエントリも展開は 1 回だけで、持っておくのはたどっている途中の base の中身だけ
+    let mut bases = vec![None; count];
+    for root in 0..count {
+        let Some((sha, _, _)) = &resolved[root] else {
+            continue;
+        };
+        let children = take_children(root, sha);
+        if children.is_empty() {
+            continue;
+        }
+        let data = Rc::new(Pack::inflate(&mut reader, &scanned[root].0)?.0);
+        let mut stack = children
+            .into_iter()
+            .map(|i| (i, root, data.clone()))
+            .collect::<Vec<_>>();
+        while let Some((i, base, base_data)) = stack.pop() {
+            let (base_sha, kind, base_depth) = resolved[base].clone().unwrap();
+            if base_depth >= MAX_DELTA_DEPTH {
+                return Err(Error::corrupt("Delta chain too deep"));
+            }
+            let delta = Pack::inflate(&mut reader, &scanned[i].0)?.0;
+            let data = apply_delta(&base_data, &delta)?;
+            let sha = object_hash_stream(&kind, data.len(), data.as_slice())?;
+            let data = Rc::new(data);
+            stack.extend(
+                take_children(i, &sha)
+                    .into_iter()
+                    .map(|child| (child, i, data.clone())),
+            );
+            resolved[i] = Some((sha, kind, base_depth + 1));
+            bases[i] = Some(base_sha);
+        }
+    }
+
+    scanned
+        .into_iter()
+        .zip(resolved)
+        .zip(bases)
+        .map(|(((entry, packed_size, crc32), resolved), base)| {
+            // pack の外にある base を使う thin pack には対応しない
+            let (sha, kind, depth) = resolved.ok_or_else(|| {
+                Error::corrupt(format!(
+                    "Delta at {} has no base in this pack",
+                    entry.offset
+                ))
+            })?;
+            Ok(ScannedEntry {
+                sha,
+                kind,
+                size: entry.size,
+                packed_size,
+                offset: entry.offset,
+                crc32,
+                depth,
+                base,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|entries| (entries, checksum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_index, IndexEntry, PackIndex, PackWriter};
+    use crate::{git_object::GitObjectKind, object_stream::object_hash_stream};
+
+    #[test]
+    fn index_round_trip() {
+        let mut pack = PackWriter::new(Vec::new(), 2).unwrap();
+        let mut entries = Vec::new();
+        for content in [&b"hi\n"[..], b"hello\n"] {
+            let sha = object_hash_stream(&GitObjectKind::Blob, content.len(), content).unwrap();
+            pack.write_object(sha, &GitObjectKind::Blob, content.len(), content)
+                .unwrap();
+            entries.push(sha);
+        }
+        let (data, mut written, checksum) = pack.finish().unwrap();
+        assert_eq!(&data[..4], b"PACK");
+        assert_eq!(&data[data.len() - 20..], checksum.as_bytes());
+
+        // 2^31 を超える offset は 64 bit の表に入る
+        written.push(IndexEntry {
+            sha: "ffffffffffffffffffffffffffffffffffffffff".parse().unwrap(),
+            offset: 1 << 33,
+            crc32: 0,
+        });
+        let (idx, _) = write_index(Vec::new(), &mut written, &checksum).unwrap();
+        let index = PackIndex::parse(&idx).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.pack_checksum, checksum);
+        for e in &written {
+            let i = index.lookup(&e.sha).unwrap();
+            assert_eq!(index.offset(i), e.offset);
+            assert_eq!(index.crc32(i), e.crc32);
+        }
+        assert_eq!(index.find_prefix("45b983"), vec![entries[0]]);
+        assert!(index.lookup(&"0".repeat(40).parse().unwrap()).is_none());
+    }
+}
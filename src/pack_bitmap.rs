@@ -0,0 +1,321 @@
+use crate::{
+    commit_graph::CommitGraph,
+    error::{Error, Result},
+    ewah::Bitmap,
+    git_object::{GitObject, GitObjectKind},
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+    object_stream::HashWriter,
+    pack::{pack_list, tmp_path, Pack, PackIndex},
+    reachable::{links, reachable_objects},
+};
+use bstr::BString;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const SIGNATURE: &[u8] = b"BITM";
+const VERSION: u16 = 1;
+// bitmap のある commit からたどれるものはすべて pack に入っている (git はこれがないと読まない)
+const OPT_FULL_DAG: u16 = 1;
+// 型ごとの bitmap と commit ごとの bitmap の後ろに、.idx の順で path の hash が並ぶ
+const OPT_HASH_CACHE: u16 = 4;
+// signature, version, option, commit の数, pack の checksum
+const HEADER_LEN: usize = 12 + ObjectId::LEN;
+// ref の先以外では、この数の commit ごとに 1 つ bitmap を作る
+const COMMIT_INTERVAL: usize = 100;
+
+// bitmap の bit の順番 (pack の中の offset 順) での .idx の各 object の位置
+fn pack_order(index: &PackIndex) -> Vec<usize> {
+    let mut by_offset = (0..index.len()).collect::<Vec<_>>();
+    by_offset.sort_by_key(|&i| index.offset(i));
+    let mut positions = vec![0; index.len()];
+    for (pos, i) in by_offset.into_iter().enumerate() {
+        positions[i] = pos;
+    }
+    positions
+}
+
+// git が delta の base を選ぶときに使う path の hash (空白は無視する)
+fn name_hash(path: &[u8]) -> u32 {
+    path.iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, &c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+// bitmap のある commit を境に、roots からたどれるものを bitmap の OR で集める
+// 境までの commit やその tree は含まない (reachable_objects でたどる)
+fn covered(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    roots: &[ObjectId],
+    bitmaps: &HashMap<ObjectId, Bitmap>,
+    position: &dyn Fn(&ObjectId) -> Option<usize>,
+) -> Result<Bitmap> {
+    let mut result = Bitmap::new();
+    let mut seen = HashSet::new();
+    let mut stack = roots.to_vec();
+    while let Some(sha) = stack.pop() {
+        if !seen.insert(sha) || position(&sha).is_some_and(|pos| result.get(pos)) {
+            continue;
+        }
+        if let Some(bitmap) = bitmaps.get(&sha) {
+            result.or(bitmap);
+            continue;
+        }
+        if let Some(commit) = graph.map(|g| g.commit(&sha)).transpose()?.flatten() {
+            stack.extend(commit.parents);
+            continue;
+        }
+        match odb.open(&sha)?.kind {
+            GitObjectKind::Commit => {
+                if let GitObject::Commit { parent, .. } = odb.read(&sha)? {
+                    stack.extend(parent);
+                }
+            }
+            GitObjectKind::Tag => stack.extend(links(&sha, &odb.read_raw(&sha)?)?),
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+// xxx.pack に対する xxx.bitmap
+pub struct PackBitmap<'a> {
+    index: &'a PackIndex,
+    positions: Vec<usize>,
+    bitmaps: HashMap<ObjectId, Bitmap>,
+}
+
+impl<'a> PackBitmap<'a> {
+    pub fn path(pack: &Pack) -> PathBuf {
+        pack.pack_path.with_extension("bitmap")
+    }
+
+    // ファイルがなければ None
+    pub fn open(pack: &'a Pack) -> Result<Option<Self>> {
+        match fs::read(Self::path(pack)) {
+            Ok(data) => Ok(Some(Self::parse(&data, &pack.index)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(data: &[u8], index: &'a PackIndex) -> Result<Self> {
+        if data.len() < HEADER_LEN + ObjectId::LEN || &data[..4] != SIGNATURE {
+            return Err(Error::corrupt("Not a bitmap file"));
+        }
+        if !PackIndex::checksum_ok(data) {
+            return Err(Error::corrupt("Bitmap file has a wrong checksum"));
+        }
+        let version = u16::from_be_bytes([data[4], data[5]]);
+        let options = u16::from_be_bytes([data[6], data[7]]);
+        if version != VERSION || options & OPT_FULL_DAG == 0 {
+            return Err(Error::corrupt(format!(
+                "Unsupported bitmap version {} (options {:#x})",
+                version, options
+            )));
+        }
+        if data[12..HEADER_LEN] != *index.pack_checksum.as_bytes() {
+            return Err(Error::corrupt("Bitmap does not match its pack"));
+        }
+        let count = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+
+        let mut pos = HEADER_LEN;
+        // commit, tree, blob, tag の bitmap は使わない
+        for _ in 0..4 {
+            Bitmap::read_ewah(data, &mut pos)?;
+        }
+        let mut entries: Vec<(ObjectId, Bitmap)> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = data
+                .get(pos..pos + 6)
+                .ok_or(Error::corrupt("Truncated bitmap entry"))?;
+            pos += 6;
+            let i = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+            // xor_offset が 0 でなければ、その数だけ前の bitmap との XOR になっている
+            let xor_offset = header[4] as usize;
+            if i >= index.len() || xor_offset > entries.len() {
+                return Err(Error::corrupt("Bitmap entry is out of range"));
+            }
+            let mut bitmap = Bitmap::read_ewah(data, &mut pos)?;
+            if xor_offset > 0 {
+                bitmap.xor(&entries[entries.len() - xor_offset].1);
+            }
+            entries.push((index.id(i), bitmap));
+        }
+        Ok(Self {
+            index,
+            positions: pack_order(index),
+            bitmaps: entries.into_iter().collect(),
+        })
+    }
+
+    fn position(&self, sha: &ObjectId) -> Option<usize> {
+        self.index.lookup(sha).map(|i| self.positions[i])
+    }
+
+    // roots からたどれるもののうち、bitmap だけでわかるもの
+    pub fn covered(
+        &self,
+        odb: &dyn ObjectDatabase,
+        graph: Option<&CommitGraph>,
+        roots: &[ObjectId],
+    ) -> Result<Bitmap> {
+        covered(odb, graph, roots, &self.bitmaps, &|sha| self.position(sha))
+    }
+
+    pub fn contains(&self, bitmap: &Bitmap, sha: &ObjectId) -> bool {
+        self.position(sha).is_some_and(|pos| bitmap.get(pos))
+    }
+}
+
+// .bitmap のある pack (git と同じく最初に見つかったものだけを使う)
+pub fn bitmapped_pack(pack_dir: &Path) -> Result<Option<Pack>> {
+    pack_list(pack_dir)?
+        .iter()
+        .find(|idx_path| idx_path.with_extension("bitmap").exists())
+        .map(|idx_path| Pack::open(idx_path))
+        .transpose()
+}
+
+// 壊れた .bitmap は警告して使わない
+pub fn load_bitmap(pack: &Pack) -> Option<PackBitmap<'_>> {
+    PackBitmap::open(pack).unwrap_or_else(|e| {
+        eprintln!("warning: ignoring bitmap: {}", e);
+        None
+    })
+}
+
+// roots からたどれるもの: bitmap でわかる pack 済みのものは Bitmap で、
+// 残りはたどって tree の中でのパスと一緒に返す
+pub fn reachable_with_bitmap(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    bitmap: Option<&PackBitmap>,
+    roots: &[ObjectId],
+) -> Result<(Bitmap, Vec<(ObjectId, BString)>)> {
+    let covered = match bitmap {
+        Some(bitmap) => bitmap.covered(odb, graph, roots)?,
+        None => Bitmap::new(),
+    };
+    let skip = |sha: &ObjectId| bitmap.is_some_and(|b| b.contains(&covered, sha));
+    let found = reachable_objects(odb, graph, roots.iter().copied(), &skip)?;
+    Ok((covered, found))
+}
+
+// tips と、それ以外の commit を新しいものから COMMIT_INTERVAL ごとに選んで bitmap を作る
+// objects は pack に入れたものとその path で、pack の外の object にたどり着いたらエラーにする
+pub fn write_bitmap(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    pack: &Pack,
+    objects: &[(ObjectId, BString)],
+    tips: &[ObjectId],
+) -> Result<usize> {
+    let index = &pack.index;
+    let positions = pack_order(index);
+    let position = |sha: &ObjectId| index.lookup(sha).map(|i| positions[i]);
+
+    let mut types = [Bitmap::new(), Bitmap::new(), Bitmap::new(), Bitmap::new()];
+    let mut commits = Vec::new();
+    let mut reader = pack.reader()?;
+    for (i, &pos) in positions.iter().enumerate() {
+        let kind = pack.kind_at(&mut reader, index.offset(i))?;
+        let n = match kind {
+            GitObjectKind::Commit => 0,
+            GitObjectKind::Tree => 1,
+            GitObjectKind::Blob => 2,
+            GitObjectKind::Tag => 3,
+        };
+        types[n].set(pos);
+        if kind == GitObjectKind::Commit {
+            let sha = index.id(i);
+            let time = match graph.map(|g| g.commit(&sha)).transpose()?.flatten() {
+                Some(commit) => commit.commit_time,
+                None => match odb.read(&sha)? {
+                    GitObject::Commit { committer, .. } => committer.timestamp(),
+                    _ => unreachable!(),
+                },
+            };
+            commits.push((time, sha));
+        }
+    }
+
+    // annotated tag は commit まで剥がす
+    let mut tip_commits = HashSet::new();
+    for tip in tips {
+        let mut sha = *tip;
+        while let Ok(reader) = odb.open(&sha) {
+            match reader.kind {
+                GitObjectKind::Tag => match links(&sha, &reader.read_to_vec()?)?.first() {
+                    Some(target) => sha = *target,
+                    None => break,
+                },
+                GitObjectKind::Commit => {
+                    tip_commits.insert(sha);
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    commits.sort_by(|a, b| b.cmp(a));
+    let mut selected = commits
+        .iter()
+        .enumerate()
+        .filter(|(n, (_, sha))| n % COMMIT_INTERVAL == 0 || tip_commits.contains(sha))
+        .map(|(_, commit)| *commit)
+        .collect::<Vec<_>>();
+    // 古いものから作り、新しい commit ではそれを OR して使う
+    selected.reverse();
+    let mut bitmaps = HashMap::new();
+    for (_, sha) in &selected {
+        let mut bitmap = covered(odb, graph, &[*sha], &bitmaps, &position)?;
+        let known = bitmap.clone();
+        let skip = |s: &ObjectId| position(s).is_some_and(|pos| known.get(pos));
+        for (found, _) in reachable_objects(odb, graph, [*sha], &skip)? {
+            let pos = position(&found).ok_or_else(|| {
+                Error::corrupt(format!("{} is reachable but not in the pack", found))
+            })?;
+            bitmap.set(pos);
+        }
+        bitmaps.insert(*sha, bitmap);
+    }
+
+    let mut w = HashWriter::new(Vec::new());
+    w.write_all(SIGNATURE)?;
+    w.write_all(&VERSION.to_be_bytes())?;
+    w.write_all(&(OPT_FULL_DAG | OPT_HASH_CACHE).to_be_bytes())?;
+    w.write_all(&(selected.len() as u32).to_be_bytes())?;
+    w.write_all(index.pack_checksum.as_bytes())?;
+    let mut data = Vec::new();
+    for bitmap in &types {
+        bitmap.write_ewah(&mut data);
+    }
+    for (_, sha) in &selected {
+        let i = index.lookup(sha).unwrap() as u32;
+        data.extend_from_slice(&i.to_be_bytes());
+        // XOR での圧縮はしない
+        data.extend_from_slice(&[0, 0]);
+        bitmaps[sha].write_ewah(&mut data);
+    }
+    let paths = objects.iter().cloned().collect::<HashMap<_, _>>();
+    for sha in index.ids() {
+        let hash = paths.get(&sha).map_or(0, |path| name_hash(path));
+        data.extend_from_slice(&hash.to_be_bytes());
+    }
+    w.write_all(&data)?;
+    let (mut data, checksum) = w.finish();
+    data.extend_from_slice(checksum.as_bytes());
+
+    let path = PackBitmap::path(pack);
+    let tmp = tmp_path(path.parent().unwrap(), "tmp_bitmap");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &path)?;
+    Ok(selected.len())
+}
@@ -0,0 +1,184 @@
+use crate::{
+    git_repository::{repo_find, GitRepository},
+    object_database::{LooseObjectDatabase, ObjectDatabase},
+    pack_bitmap::{bitmapped_pack, load_bitmap, reachable_with_bitmap},
+    reachable::{index_roots, ref_roots, reflog_roots},
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local, Months, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::collections::HashSet;
+
+// --expire に渡す日時。None なら何も期限切れにならない
+// "now", "never", "2.weeks.ago" (または "2 weeks ago"), "2024-01-31", "2024-01-31 12:00:00",
+// RFC 3339, "@1700000000" を受け付ける
+pub fn parse_expire(s: &str, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    let s = s.trim();
+    let invalid = || anyhow::anyhow!("Invalid expiry date {}", s);
+    match s {
+        "now" | "all" => return Ok(Some(now)),
+        "never" | "false" => return Ok(None),
+        _ => {}
+    }
+    if let Some(time) = s.strip_prefix('@') {
+        let time = time.parse().map_err(|_| invalid())?;
+        return DateTime::from_timestamp(time, 0)
+            .map(Some)
+            .ok_or_else(invalid);
+    }
+
+    let words = s
+        .split(['.', ' '])
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+    if let [n, unit, "ago"] = words[..] {
+        let n: u32 = n.parse().map_err(|_| invalid())?;
+        let ago = match unit.strip_suffix('s').unwrap_or(unit) {
+            "second" => now.checked_sub_signed(Duration::seconds(n.into())),
+            "minute" => now.checked_sub_signed(Duration::minutes(n.into())),
+            "hour" => now.checked_sub_signed(Duration::hours(n.into())),
+            "day" => now.checked_sub_signed(Duration::days(n.into())),
+            "week" => now.checked_sub_signed(Duration::weeks(n.into())),
+            "month" => now.checked_sub_months(Months::new(n)),
+            "year" => now.checked_sub_months(Months::new(n.saturating_mul(12))),
+            _ => return Err(invalid()),
+        };
+        return ago.map(Some).ok_or_else(invalid);
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    // タイムゾーンのないものは手元の時刻として読む
+    let local = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_time(Default::default()))
+        })
+        .map_err(|_| invalid())?;
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| Some(time.with_timezone(&Utc)))
+        .ok_or_else(invalid)
+}
+
+// expire を指定しなければ古さに関係なく消す
+pub fn cmd_prune(expire: Option<String>, dry_run: bool) -> Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    let expire = match expire {
+        Some(expire) => match parse_expire(&expire, Utc::now())? {
+            Some(expire) => expire,
+            None => return Ok(()),
+        },
+        None => DateTime::<Utc>::MAX_UTC,
+    };
+    let pruned = prune(&repo, expire, dry_run)?;
+    if !dry_run {
+        println!("Removed {} unreachable loose objects", pruned);
+    }
+    Ok(())
+}
+
+// ref, HEAD, reflog, index のどれからもたどれない loose object のうち、expire より古いものを消す
+// 消した (dry_run なら消す) 数を返す
+pub fn prune(repo: &GitRepository, expire: DateTime<Utc>, dry_run: bool) -> Result<usize> {
+    let loose = LooseObjectDatabase::new(repo.objects_dir.clone());
+    let mut roots = ref_roots(&repo.gitdir)?;
+    roots.extend(reflog_roots(&repo.gitdir, repo.odb())?);
+    roots.extend(index_roots(&repo.gitdir, repo.odb())?);
+    let pack = bitmapped_pack(&repo.objects_dir.join("pack"))?;
+    let bitmap = pack.as_ref().and_then(load_bitmap);
+    let (covered, found) =
+        reachable_with_bitmap(repo.odb(), repo.commit_graph(), bitmap.as_ref(), &roots)?;
+    let reachable = found
+        .into_iter()
+        .map(|(sha, _)| sha)
+        .collect::<HashSet<_>>();
+
+    let mut pruned = 0;
+    for sha in loose.iter()?.collect::<Vec<_>>() {
+        if reachable.contains(&sha) || bitmap.as_ref().is_some_and(|b| b.contains(&covered, &sha)) {
+            continue;
+        }
+        // 書いたばかりでまだ ref から指されていないものは残す
+        if DateTime::<Utc>::from(loose.modified(&sha)?) > expire {
+            continue;
+        }
+        if dry_run {
+            let kind = loose.open(&sha)?.kind;
+            println!("{} {}", sha, kind.as_str());
+        } else {
+            loose.remove(&sha)?;
+        }
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_expire, prune};
+    use crate::{
+        add::{add, AddOptions},
+        git_object::GitObject,
+        git_repository::{repo_create, repo_find},
+    };
+    use chrono::{DateTime, Duration, Utc};
+    use std::fs;
+
+    #[test]
+    fn expire() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let parse = |s: &str| parse_expire(s, now).unwrap();
+        assert_eq!(parse("now"), Some(now));
+        assert_eq!(parse("never"), None);
+        assert_eq!(parse("2.weeks.ago"), Some(now - Duration::weeks(2)));
+        assert_eq!(parse("1 hour ago"), Some(now - Duration::hours(1)));
+        assert_eq!(parse("3.days.ago"), Some(now - Duration::days(3)));
+        assert_eq!(
+            parse("1.month.ago"),
+            DateTime::from_timestamp(1_700_000_000 - 31 * 86400, 0)
+        );
+        assert_eq!(
+            parse("@1600000000"),
+            DateTime::from_timestamp(1_600_000_000, 0)
+        );
+        assert_eq!(
+            parse("2023-11-14T22:13:20Z"),
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+        assert!(parse("2023-11-14").is_some());
+        assert!(parse_expire("2.fortnights.ago", now).is_err());
+        assert!(parse_expire("yesterday-ish", now).is_err());
+    }
+
+    #[test]
+    fn keeps_staged_objects() {
+        let dir = std::env::temp_dir().join(format!("our_git-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        repo_create(&dir).unwrap();
+        let repo = repo_find(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "staged\n").unwrap();
+        let options = AddOptions {
+            all: false,
+            update: false,
+            force: false,
+        };
+        add(&repo, &dir, &["a.txt".into()], &options).unwrap();
+        let staged = GitObject::Blob {
+            content: b"staged\n".to_vec(),
+        }
+        .write(repo.odb())
+        .unwrap();
+        let dangling = GitObject::Blob {
+            content: b"dangling\n".to_vec(),
+        }
+        .write(repo.odb())
+        .unwrap();
+
+        assert_eq!(prune(&repo, Utc::now(), false).unwrap(), 1);
+        assert!(repo.odb().contains(&staged));
+        assert!(!repo.odb().contains(&dangling));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    commit_graph::CommitGraph,
+    error::{Error, Result},
+    git_object::{parse_commit, tree_parse, FileType, GitObject, GitObjectKind},
+    index::{Index, GITLINK_MODE},
+    object_database::ObjectDatabase,
+    object_id::ObjectId,
+    show_ref::{packed_ref_list, ref_list, ref_resolve},
+};
+use bstr::{BString, ByteSlice};
+use std::{collections::HashSet, fs, path::Path};
+
+// refs/ 以下のすべての ref (packed-refs にあるものも) と HEAD が指すオブジェクト
+pub fn ref_roots(gitdir: &Path) -> anyhow::Result<Vec<ObjectId>> {
+    // 同じ名前なら loose の ref が新しい
+    let mut refs = packed_ref_list(gitdir)?;
+    refs.extend(ref_list(gitdir, &gitdir.join("refs"))?);
+    let mut roots = refs.into_values().collect::<Vec<_>>();
+    // まだコミットのないブランチを指す HEAD は無視する
+    if let Ok(head) = ref_resolve(gitdir, &gitdir.join("HEAD")) {
+        roots.push(head);
+    }
+    Ok(roots)
+}
+
+// add しただけでまだ commit していないもの
+// gitlink は別のリポジトリの commit なので含めない
+pub fn index_roots(gitdir: &Path, odb: &dyn ObjectDatabase) -> Result<Vec<ObjectId>> {
+    Ok(Index::read(gitdir)?
+        .entries
+        .into_iter()
+        .filter(|e| e.mode != GITLINK_MODE && odb.contains(&e.sha))
+        .map(|e| e.sha)
+        .collect())
+}
+
+// logs/ 以下の reflog に残っている ref の過去の値
+// "<old> <new> <committer>\t<message>" の old と new のうち、まだあるものだけを返す
+pub fn reflog_roots(gitdir: &Path, odb: &dyn ObjectDatabase) -> anyhow::Result<Vec<ObjectId>> {
+    let mut roots = Vec::new();
+    let mut stack = vec![gitdir.join("logs")];
+    while let Some(path) = stack.pop() {
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                stack.push(entry?.path());
+            }
+            continue;
+        }
+        if !path.is_file() {
+            continue;
+        }
+        for line in fs::read(&path)?.lines() {
+            for field in line.splitn_str(3, " ").take(2) {
+                let Some(sha) = field.to_str().ok().and_then(|s| s.parse().ok()) else {
+                    continue;
+                };
+                if odb.contains(&sha) {
+                    roots.push(sha);
+                }
+            }
+        }
+    }
+    Ok(roots)
+}
+
+// commit と tag が指すオブジェクト
+// tagger などが壊れていてもたどれるよう、ヘッダを key と value に分けるだけにする
+pub fn links(sha: &ObjectId, data: &[u8]) -> Result<Vec<ObjectId>> {
+    let mut kvlm = Vec::new();
+    parse_commit(data, 0, &mut kvlm).map_err(|e| e.with_sha(sha))?;
+    kvlm.into_iter()
+        .filter(|(key, _)| key == "tree" || key == "parent" || key == "object")
+        .map(|(_, value)| {
+            value
+                .to_string()
+                .parse()
+                .map_err(|_| Error::corrupt(format!("Invalid object id {}", value)).with_sha(sha))
+        })
+        .collect()
+}
+
+// roots からたどれるオブジェクトを見つけた順に、tree の中でのパスと一緒に返す
+// blob は中身を読まない。submodule の commit は別のリポジトリのものなので含めない
+// commit-graph にある commit は読まずに tree と親をそこから取る
+// skip が true を返すオブジェクトとその先はたどらない (bitmap でわかっているものなど)
+pub fn reachable_objects(
+    odb: &dyn ObjectDatabase,
+    graph: Option<&CommitGraph>,
+    roots: impl IntoIterator<Item = ObjectId>,
+    skip: &dyn Fn(&ObjectId) -> bool,
+) -> Result<Vec<(ObjectId, BString)>> {
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    let mut stack = roots
+        .into_iter()
+        .map(|sha| (sha, BString::from("")))
+        .collect::<Vec<_>>();
+    stack.reverse();
+    while let Some((sha, path)) = stack.pop() {
+        if !seen.insert(sha) || skip(&sha) {
+            continue;
+        }
+        found.push((sha, path.clone()));
+        if let Some(commit) = graph.map(|g| g.commit(&sha)).transpose()?.flatten() {
+            stack.extend(
+                commit
+                    .parents
+                    .into_iter()
+                    .rev()
+                    .map(|sha| (sha, BString::from(""))),
+            );
+            stack.push((commit.tree, BString::from("")));
+            continue;
+        }
+        let reader = odb.open(&sha)?;
+        let kind = reader.kind.clone();
+        if kind == GitObjectKind::Blob {
+            continue;
+        }
+        let data = reader.read_to_vec().map_err(|e| e.with_sha(&sha))?;
+        if kind != GitObjectKind::Tree {
+            stack.extend(
+                links(&sha, &data)?
+                    .into_iter()
+                    .rev()
+                    .map(|sha| (sha, BString::from(""))),
+            );
+            continue;
+        }
+        if let GitObject::Tree(entries) = tree_parse(&data).map_err(|e| e.with_sha(&sha))? {
+            for entry in entries.into_iter().rev() {
+                let path = if path.is_empty() {
+                    entry.path
+                } else {
+                    BString::from([path.as_slice(), b"/", entry.path.as_slice()].concat())
+                };
+                match entry.file_type {
+                    FileType::Submodule => {}
+                    // blob は読まずに済ませる
+                    FileType::RegularFile | FileType::SymbolicLink => {
+                        if !skip(&entry.sha) && seen.insert(entry.sha) {
+                            found.push((entry.sha, path));
+                        }
+                    }
+                    FileType::Tree => stack.push((entry.sha, path)),
+                }
+            }
+        }
+    }
+    Ok(found)
+}
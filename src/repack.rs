@@ -0,0 +1,171 @@
+use crate::{
+    git_repository::{repo_find, GitRepository},
+    multi_pack_index::MultiPackIndex,
+    object_database::{LooseObjectDatabase, ObjectDatabase},
+    pack::{pack_list, pack_objects, DeltaOptions, Pack},
+    pack_bitmap::{bitmapped_pack, load_bitmap, reachable_with_bitmap, write_bitmap},
+    reachable::{index_roots, reachable_objects, ref_roots, reflog_roots},
+};
+use anyhow::Result;
+use std::{fs, path::Path};
+
+pub struct RepackOptions {
+    // pack 済みのものも含めてすべてを 1 つの pack にする
+    pub all: bool,
+    // pack に入れた loose object と、-a なら古い pack を消す
+    pub delete: bool,
+    // -a で作った pack に .bitmap を付ける
+    pub write_bitmap: bool,
+    pub delta: DeltaOptions,
+}
+
+pub fn cmd_repack(options: RepackOptions) -> Result<()> {
+    let current_dir = std::env::current_dir()?;
+    let repo = repo_find(&current_dir)?;
+    repack(&repo, &options)
+}
+
+// .keep のある pack は -a でも消さず、中の object も新しい pack に入れない
+fn is_kept(idx_path: &Path) -> bool {
+    idx_path.with_extension("keep").exists()
+}
+
+pub fn repack(repo: &GitRepository, options: &RepackOptions) -> Result<()> {
+    let loose = LooseObjectDatabase::new(repo.objects_dir.clone());
+    let pack_dir = repo.objects_dir.join("pack");
+    anyhow::ensure!(
+        options.all || !options.write_bitmap,
+        "bitmaps can only be written with -a"
+    );
+    let tips = ref_roots(&repo.gitdir)?;
+    // reflog や index にしか残っていないものも捨てない
+    let mut roots = tips.clone();
+    roots.extend(reflog_roots(&repo.gitdir, repo.odb())?);
+    roots.extend(index_roots(&repo.gitdir, repo.odb())?);
+
+    let kept = pack_list(&pack_dir)?
+        .into_iter()
+        .filter(|idx_path| is_kept(idx_path))
+        .map(|idx_path| Pack::open(&idx_path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ids = if options.all {
+        let mut ids = reachable_objects(repo.odb(), repo.commit_graph(), roots, &|_| false)?;
+        ids.retain(|(sha, _)| kept.iter().all(|pack| pack.index.lookup(sha).is_none()));
+        ids
+    } else {
+        // bitmap でわかる pack 済みのものはたどらない
+        let pack = bitmapped_pack(&pack_dir)?;
+        let bitmap = pack.as_ref().and_then(load_bitmap);
+        let (_, found) =
+            reachable_with_bitmap(repo.odb(), repo.commit_graph(), bitmap.as_ref(), &roots)?;
+        // pack 済みのものは対象にしない
+        found
+            .into_iter()
+            .filter(|(sha, _)| loose.contains(sha))
+            .collect::<Vec<_>>()
+    };
+    if ids.is_empty() {
+        println!("Nothing new to pack.");
+        return Ok(());
+    }
+
+    let old_packs = pack_list(&pack_dir)?;
+    let pack_path = pack_objects(repo.odb(), &pack_dir, &ids, &options.delta)?;
+    println!("Packed {} objects into {}", ids.len(), pack_path.display());
+
+    if options.write_bitmap && !kept.is_empty() {
+        // bitmap はたどれるものがすべて同じ pack にないと作れない
+        eprintln!("warning: disabling bitmap writing, as some objects are in kept packs");
+    } else if options.write_bitmap {
+        let pack = Pack::open(&pack_path.with_extension("idx"))?;
+        let count = write_bitmap(repo.odb(), repo.commit_graph(), &pack, &ids, &tips)?;
+        println!("Wrote bitmaps for {} commits", count);
+    }
+
+    if options.delete {
+        let packed = ids
+            .iter()
+            .filter(|(sha, _)| loose.contains(sha))
+            .collect::<Vec<_>>();
+        for (sha, _) in &packed {
+            loose.remove(sha)?;
+        }
+        println!("Removed {} loose objects", packed.len());
+
+        if options.all {
+            let old_packs = old_packs
+                .iter()
+                .filter(|idx_path| **idx_path != pack_path.with_extension("idx"))
+                .filter(|idx_path| !is_kept(idx_path))
+                .collect::<Vec<_>>();
+            for idx_path in &old_packs {
+                for ext in ["pack", "bitmap", "idx"] {
+                    match fs::remove_file(idx_path.with_extension(ext)) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+            }
+            // 消した pack を指しているので作り直すまで使わない
+            if !old_packs.is_empty() {
+                match fs::remove_file(MultiPackIndex::path(&pack_dir)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            println!("Removed {} old packs", old_packs.len());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{repack, RepackOptions};
+    use crate::{
+        add::{add, AddOptions},
+        git_repository::{repo_create, repo_find},
+        pack::{pack_list, DeltaOptions, Pack},
+    };
+    use std::fs;
+
+    #[test]
+    fn keeps_kept_packs() {
+        let dir = std::env::temp_dir().join(format!("our_git-repack-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        repo_create(&dir).unwrap();
+        let repo = repo_find(&dir).unwrap();
+        let pack_dir = repo.objects_dir.join("pack");
+        let add_file = |name: &str| {
+            fs::write(dir.join(name), name).unwrap();
+            let options = AddOptions {
+                all: false,
+                update: false,
+                force: false,
+            };
+            add(&repo, &dir, &[name.into()], &options).unwrap();
+        };
+        let options = RepackOptions {
+            all: true,
+            delete: true,
+            write_bitmap: false,
+            delta: DeltaOptions::default(),
+        };
+
+        add_file("a.txt");
+        repack(&repo, &options).unwrap();
+        let kept = pack_list(&pack_dir).unwrap();
+        assert_eq!(kept.len(), 1);
+        fs::write(kept[0].with_extension("keep"), "").unwrap();
+
+        add_file("b.txt");
+        repack(&repo, &options).unwrap();
+        let packs = pack_list(&pack_dir).unwrap();
+        assert_eq!(packs.len(), 2);
+        assert!(kept[0].exists() && kept[0].with_extension("pack").exists());
+        let new = packs.iter().find(|p| **p != kept[0]).unwrap();
+        // 新しい pack には kept pack にないものだけが入る
+        assert_eq!(Pack::open(new).unwrap().index.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
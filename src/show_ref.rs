@@ -1,10 +1,10 @@
-use crate::git_repository::repo_find;
+use crate::{error::Error, git_repository::repo_find, object_id::ObjectId};
 use anyhow::Result;
 use std::{
     collections::BTreeMap,
-    fs::{read_dir, File},
-    io::Read,
-    path::PathBuf,
+    fs::{self, read_dir, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
 };
 
 pub fn cmd_show_ref() -> Result<()> {
@@ -15,20 +15,29 @@ pub fn cmd_show_ref() -> Result<()> {
     show_ref(refs, true)
 }
 
-fn ref_resolve(gitdir: &PathBuf, ref_path: &PathBuf) -> Result<String> {
-    let mut f = File::open(ref_path)?;
+pub fn ref_resolve(gitdir: &Path, ref_path: &Path) -> Result<ObjectId> {
+    let invalid = || Error::InvalidRef(ref_path.display().to_string());
+    let mut f = match File::open(ref_path) {
+        Ok(f) => f,
+        // pack-refs でまとめられた ref は packed-refs から探す
+        Err(_) => {
+            let name = ref_path.strip_prefix(gitdir).map_err(|_| invalid())?;
+            let sha = packed_ref_list(gitdir)?.get(name).copied();
+            return Ok(sha.ok_or_else(invalid)?);
+        }
+    };
     let mut buf = String::new();
-    f.read_to_string(&mut buf)?;
+    f.read_to_string(&mut buf).map_err(|_| invalid())?;
     let buf = buf.trim().to_string();
     if buf.starts_with("ref: ") {
         let ref_path = buf.trim_start_matches("ref: ");
         ref_resolve(gitdir, &gitdir.join(ref_path))
     } else {
-        Ok(buf)
+        Ok(buf.parse().map_err(|_| invalid())?)
     }
 }
 
-pub fn ref_list(gitdir: &PathBuf, current: &PathBuf) -> Result<BTreeMap<PathBuf, String>> {
+pub fn ref_list(gitdir: &Path, current: &Path) -> Result<BTreeMap<PathBuf, ObjectId>> {
     let mut refs = BTreeMap::new();
     for entry in read_dir(current)? {
         let path = entry?.path();
@@ -42,7 +51,27 @@ pub fn ref_list(gitdir: &PathBuf, current: &PathBuf) -> Result<BTreeMap<PathBuf,
     Ok(refs)
 }
 
-pub fn show_ref(refs: BTreeMap<PathBuf, String>, with_hash: bool) -> Result<()> {
+// git pack-refs で .git/packed-refs にまとめられた ref
+// "^" で始まる行は直前の tag を剥がした先なので読まない
+pub fn packed_ref_list(gitdir: &Path) -> Result<BTreeMap<PathBuf, ObjectId>> {
+    let mut refs = BTreeMap::new();
+    let data = match fs::read_to_string(gitdir.join("packed-refs")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(refs),
+        Err(e) => return Err(e.into()),
+    };
+    for line in data.lines() {
+        if line.is_empty() || line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let invalid = || Error::InvalidRef(line.to_string());
+        let (sha, name) = line.split_once(' ').ok_or_else(invalid)?;
+        refs.insert(PathBuf::from(name), sha.parse().map_err(|_| invalid())?);
+    }
+    Ok(refs)
+}
+
+pub fn show_ref(refs: BTreeMap<PathBuf, ObjectId>, with_hash: bool) -> Result<()> {
     for (k, v) in refs {
         if with_hash {
             println!("{} -> {}", k.display(), v)
@@ -52,3 +81,40 @@ pub fn show_ref(refs: BTreeMap<PathBuf, String>, with_hash: bool) -> Result<()>
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ref_resolve;
+    use std::fs;
+
+    #[test]
+    fn resolve_packed() {
+        let gitdir = std::env::temp_dir().join(format!("our_git-show-ref-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&gitdir);
+        fs::create_dir_all(gitdir.join("refs/heads")).unwrap();
+        let sha = "2c5accee2db0cc836283d58124599587c062b7b3";
+        fs::write(
+            gitdir.join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted\n{} refs/heads/main\n",
+                sha
+            ),
+        )
+        .unwrap();
+        fs::write(gitdir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert_eq!(
+            ref_resolve(&gitdir, &gitdir.join("HEAD")).unwrap(),
+            sha.parse().unwrap()
+        );
+
+        // loose の ref があればそちらを使う
+        let loose = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        fs::write(gitdir.join("refs/heads/main"), format!("{}\n", loose)).unwrap();
+        assert_eq!(
+            ref_resolve(&gitdir, &gitdir.join("HEAD")).unwrap(),
+            loose.parse().unwrap()
+        );
+        assert!(ref_resolve(&gitdir, &gitdir.join("refs/heads/missing")).is_err());
+        fs::remove_dir_all(&gitdir).unwrap();
+    }
+}
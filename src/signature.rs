@@ -0,0 +1,206 @@
+use bstr::{BString, ByteSlice};
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use std::{convert::Infallible, fmt, str::FromStr};
+
+// author, committer, tagger の "Name <email> 1700000000 +0900"
+// 古い履歴には形の崩れた ident もあるので、読めない部分があっても object 全体は読めるようにする
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub name: BString,
+    pub email: BString,
+    // 日時が読めなければ None
+    pub time: Option<DateTime<Utc>>,
+    // タイムゾーンが読めなければ +0000
+    pub tz_offset: FixedOffset,
+    // 読んだときのバイト列 ("-0000" や空白の入れ方は上のフィールドからは戻せない)
+    raw: Option<BString>,
+}
+
+// raw は比べない ("+0000" と "-0000" は同じ日時)
+impl PartialEq for Signature {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.email == other.email
+            && self.time == other.time
+            && self.tz_offset == other.tz_offset
+    }
+}
+
+impl Eq for Signature {}
+
+impl Signature {
+    pub fn now(name: BString, email: BString) -> Self {
+        let now = Local::now();
+        Self {
+            name,
+            email,
+            time: Some(now.with_timezone(&Utc)),
+            tz_offset: *now.offset(),
+            raw: None,
+        }
+    }
+
+    // 記録されたタイムゾーンでの日時
+    pub fn local_time(&self) -> Option<DateTime<FixedOffset>> {
+        self.time.map(|time| time.with_timezone(&self.tz_offset))
+    }
+
+    // commit の日時として使う UNIX 時刻 (読めなければ git と同じく 0)
+    pub fn timestamp(&self) -> i64 {
+        self.time.map_or(0, |time| time.timestamp())
+    }
+}
+
+impl Signature {
+    // <email> がなければ全体を名前とする
+    pub fn parse(s: &[u8]) -> Self {
+        let (name, email, date) = match (s.find_byte(b'<'), s.find_byte(b'>')) {
+            (Some(lt), Some(gt)) if lt < gt => {
+                let name = &s[..lt];
+                (
+                    name.strip_suffix(b" ").unwrap_or(name),
+                    &s[lt + 1..gt],
+                    &s[gt + 1..],
+                )
+            }
+            _ => (s, &b""[..], &b""[..]),
+        };
+        let mut date = date.fields().filter_map(|field| field.to_str().ok());
+        let time = date
+            .next()
+            .and_then(|time| time.parse().ok())
+            .and_then(|time| DateTime::from_timestamp(time, 0));
+        let tz_offset = date.next().and_then(parse_tz_offset);
+        Self {
+            name: name.into(),
+            email: email.into(),
+            time,
+            tz_offset: tz_offset.unwrap_or(FixedOffset::east_opt(0).unwrap()),
+            raw: Some(s.into()),
+        }
+    }
+
+    // object に書き込むバイト列
+    // 読んだときから変わっていなければ、SHA-1 が変わらないよう読んだときのまま書き戻す
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if let Some(raw) = &self.raw {
+            if Self::parse(raw) == *self {
+                return raw.to_vec();
+            }
+        }
+        let offset = self.tz_offset.local_minus_utc();
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs() / 60;
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&self.name);
+        ret.extend_from_slice(b" <");
+        ret.extend_from_slice(&self.email);
+        ret.push(b'>');
+        if let Some(time) = self.time {
+            ret.extend_from_slice(
+                format!(
+                    " {} {}{:02}{:02}",
+                    time.timestamp(),
+                    sign,
+                    offset / 60,
+                    offset % 60
+                )
+                .as_bytes(),
+            );
+        }
+        ret
+    }
+}
+
+impl FromStr for Signature {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Infallible> {
+        Ok(Signature::parse(s.as_bytes()))
+    }
+}
+
+// "+0900" -> +09:00
+fn parse_tz_offset(tz: &str) -> Option<FixedOffset> {
+    if tz.len() != 5 || !tz[1..].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let sign = match &tz[..1] {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let hours: i32 = tz[1..3].parse().ok()?;
+    let minutes: i32 = tz[3..5].parse().ok()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_bytes().as_bstr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Signature;
+
+    #[test]
+    fn round_trip() {
+        for s in [
+            "Thibault Polge <thibault@thb.lt> 1527025023 +0200",
+            "A <a@b> 1700000000 -0930",
+            " <> 0 +0000",
+        ] {
+            let sig: Signature = s.parse().unwrap();
+            assert_eq!(sig.to_string(), s);
+        }
+        let sig: Signature = "A B <a@b> 1700000000 +0900".parse().unwrap();
+        assert_eq!(sig.name, "A B");
+        assert_eq!(
+            sig.local_time().unwrap().to_rfc3339(),
+            "2023-11-15T07:13:20+09:00"
+        );
+
+        // Latin-1 の名前もそのまま書き戻せる
+        let latin1 = b"Ren\xe9 <r@e> 1700000000 +0100";
+        assert_eq!(Signature::parse(latin1).to_bytes(), latin1);
+
+        // "-0000" や余分な空白も読んだときのまま
+        for s in [
+            "A <a@b> 1700000000 -0000",
+            "A<a@b>  1700000000 +0000",
+            "A  <a@b> 1700000000 +0900",
+        ] {
+            assert_eq!(s.parse::<Signature>().unwrap().to_string(), s);
+        }
+        let mut sig: Signature = "A <a@b> 1700000000 -0000".parse().unwrap();
+        assert_eq!(sig, "A <a@b> 1700000000 +0000".parse().unwrap());
+        sig.name = "B".into();
+        assert_eq!(sig.to_string(), "B <a@b> 1700000000 +0000");
+    }
+
+    #[test]
+    fn malformed() {
+        // 形が崩れていても読めて、そのまま書き戻せる
+        for s in [
+            "A <a@b>",
+            "A <a@b> 1 +09",
+            "A <a@b> soon +0900",
+            "no email 1700000000 +0000",
+            "A <a@b> 99999999999999999999 +0000",
+            "",
+        ] {
+            let sig = Signature::parse(s.as_bytes());
+            assert_eq!(sig.to_string(), s);
+        }
+        let sig = Signature::parse(b"A <a@b> 1700000000 +09");
+        assert_eq!(
+            (sig.name.as_slice(), sig.timestamp()),
+            (&b"A"[..], 1_700_000_000)
+        );
+        let sig = Signature::parse(b"A <a@b>");
+        assert_eq!((sig.time, sig.timestamp()), (None, 0));
+        assert!(sig.local_time().is_none());
+    }
+}
@@ -1,10 +1,13 @@
-use std::fs;
-use anyhow::{Ok, Result};
 use crate::{
-    git_object::{GitObject, GitObjectKind},
-    git_repository::repo_find,
+    git_config::GitConfig,
+    git_object::{object_resolve, GitObject},
+    git_repository::{repo_find, GitRepository},
+    object_id::ObjectId,
     show_ref::{ref_list, show_ref},
+    signature::Signature,
 };
+use anyhow::{Ok, Result};
+use std::{fs, path::Path};
 
 pub fn cmd_ls_tag() -> Result<()> {
     let current_dir = std::env::current_dir()?;
@@ -14,47 +17,93 @@ pub fn cmd_ls_tag() -> Result<()> {
     show_ref(refs, true)
 }
 
-// objectはrefの可能性もあるが、一旦SHA-1のみを受け付ける
-// 7.6で実装予定のobject_resolveにて解決予定
+// objectはrefの可能性もあるが、一旦SHA-1 (短縮形を含む) のみを受け付ける
 pub fn cmd_tag(name: String, annotate: bool, object: String) -> Result<()> {
-    let tagger = "tagger <tagger1919@example.com>".to_string();
+    let repo = repo_find(&std::env::current_dir()?)?;
+    let object = object_resolve(repo.odb(), &object)?;
     let message = "tag message".to_string();
     if annotate {
-        create_tag_object(name, object, tagger, message)
+        let tagger = GitConfig::read(&repo.gitdir.join("config"))?
+            .signature("GIT_COMMITTER_NAME", "GIT_COMMITTER_EMAIL")?;
+        create_tag_object(&repo, name, object, tagger, message)?;
+        Ok(())
     } else {
-        create_lightweight_tag(name, object)
+        create_lightweight_tag(&repo.gitdir, name, object)
     }
 }
 
-fn create_tag_object(name: String, object: String, tagger: String, message: String) -> Result<()> {
-    let sha = object;
+fn create_tag_object(
+    repo: &GitRepository,
+    name: String,
+    object: ObjectId,
+    tagger: Signature,
+    message: String,
+) -> Result<ObjectId> {
+    // type には指している object の種類を書く (commit とは限らない)
+    let kind = repo.odb().open(&object)?.kind;
     let tag = GitObject::Tag {
-        object: sha,
-        kind: GitObjectKind::Commit,
-        tag: name.clone(),
-        tagger,
-        message,
+        object,
+        kind,
+        tag: name.clone().into(),
+        tagger: Some(tagger),
+        extra_headers: vec![],
+        message: message.into(),
     };
-    let gitdir = repo_find(&std::env::current_dir()?)?.gitdir;
-    tag.write(&gitdir)?;
-
-    let tag_sha = tag.hash()?;
-    let tags_dir = gitdir.join("refs").join("tags");
-    fs::create_dir_all(&tags_dir)?;
-    fs::write(&tags_dir.join(&name), tag_sha.clone() + "\n")?;
-
-    create_ref(name, tag_sha)
+    let tag_sha = tag.write(repo.odb())?;
+    create_ref(&repo.gitdir, name, tag_sha)?;
+    Ok(tag_sha)
 }
 
-fn create_lightweight_tag(ref_name: String, object: String) -> Result<()> {
-    create_ref(ref_name, object)
+fn create_lightweight_tag(gitdir: &Path, ref_name: String, object: ObjectId) -> Result<()> {
+    create_ref(gitdir, ref_name, object)
 }
 
-fn create_ref(ref_name: String, sha: String) -> Result<()> {
-    let gitdir = repo_find(&std::env::current_dir()?)?.gitdir;
+fn create_ref(gitdir: &Path, ref_name: String, sha: ObjectId) -> Result<()> {
     let tags_dir = gitdir.join("refs").join("tags");
+    fs::create_dir_all(&tags_dir)?;
     let ref_path = tags_dir.join(ref_name);
-    fs::write(&ref_path, sha + "\n")?;
+    fs::write(&ref_path, format!("{}\n", sha))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::create_tag_object;
+    use crate::{
+        git_object::{FileType, GitObject, GitObjectKind, TreeOject},
+        git_repository::{repo_create, repo_find},
+    };
+    use std::fs;
+
+    #[test]
+    fn tag_tree() {
+        let dir = std::env::temp_dir().join(format!("our_git-tag-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        repo_create(&dir).unwrap();
+        let repo = repo_find(&dir).unwrap();
+        let blob = GitObject::Blob {
+            content: b"a\n".to_vec(),
+        }
+        .write(repo.odb())
+        .unwrap();
+        let tree = GitObject::Tree(vec![TreeOject {
+            file_type: FileType::RegularFile,
+            permission: "0644".to_string(),
+            path: "a.txt".into(),
+            sha: blob,
+        }])
+        .write(repo.odb())
+        .unwrap();
+        let tagger = "A <a@b> 1700000000 +0900".parse().unwrap();
+        let tag = create_tag_object(&repo, "v1".into(), tree, tagger, "m\n".into()).unwrap();
+
+        let GitObject::Tag { object, kind, .. } = repo.odb().read(&tag).unwrap() else {
+            panic!("tag expected");
+        };
+        assert_eq!((object, kind), (tree, GitObjectKind::Tree));
+        let ref_path = repo.gitdir.join("refs/tags/v1");
+        assert_eq!(fs::read_to_string(ref_path).unwrap(), format!("{}\n", tag));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use crate::pack::{scan_pack, PackIndex};
+use anyhow::Result;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+// .idx と .pack が壊れていないか、お互いに一致しているかを確かめる
+pub fn cmd_verify_pack(verbose: bool, path: PathBuf) -> Result<()> {
+    // .pack を渡されても .idx を渡されてもよい
+    let idx_path = path.with_extension("idx");
+    let pack_path = path.with_extension("pack");
+    let data = fs::read(&idx_path)?;
+    anyhow::ensure!(
+        PackIndex::checksum_ok(&data),
+        "{} has a wrong checksum",
+        idx_path.display()
+    );
+    let index = PackIndex::parse(&data)?;
+    let (entries, checksum) = scan_pack(&pack_path)?;
+    anyhow::ensure!(
+        checksum == index.pack_checksum,
+        "{} does not match {}",
+        idx_path.display(),
+        pack_path.display()
+    );
+    anyhow::ensure!(
+        entries.len() == index.len(),
+        "{} has {} objects but its index has {}",
+        pack_path.display(),
+        entries.len(),
+        index.len()
+    );
+    for e in &entries {
+        let i = index
+            .lookup(&e.sha)
+            .ok_or_else(|| anyhow::anyhow!("{} is missing from the index", e.sha))?;
+        anyhow::ensure!(
+            index.offset(i) == e.offset && index.crc32(i) == e.crc32,
+            "index entry for {} does not match the packed object",
+            e.sha
+        );
+    }
+
+    if verbose {
+        let mut chains = BTreeMap::new();
+        for e in &entries {
+            print!(
+                "{} {:<6} {} {} {}",
+                e.sha,
+                e.kind.as_str(),
+                e.size,
+                e.packed_size,
+                e.offset
+            );
+            match e.base {
+                Some(base) => {
+                    println!(" {} {}", e.depth, base);
+                    *chains.entry(e.depth).or_insert(0) += 1;
+                }
+                None => println!(),
+            }
+        }
+        let non_delta = entries.iter().filter(|e| e.base.is_none()).count();
+        println!("non delta: {} {}", non_delta, objects(non_delta));
+        for (depth, n) in chains {
+            println!("chain length = {}: {} {}", depth, n, objects(n));
+        }
+    }
+    println!("{}: ok", pack_path.display());
+    Ok(())
+}
+
+fn objects(n: usize) -> &'static str {
+    if n == 1 {
+        "object"
+    } else {
+        "objects"
+    }
+}